@@ -27,12 +27,16 @@ use esp_hal::{
         systimer::SystemTimer,
         timg::{TimerGroup, Wdt},
     },
+    usb_serial_jtag::UsbSerialJtag,
     Async, Blocking,
 };
 use esp_storage::FlashStorage;
 use esp_wifi::wifi::{WifiController, WifiDevice, WifiStaDevice};
 use static_cell::StaticCell;
 
+/// Revision of the board this support package is for.
+pub const HARDWARE_REVISION: &str = "rev1";
+
 pub type I2cInstance = I2C<'static, I2C0, Async>;
 pub type ClocksInstance = Clocks<'static>;
 
@@ -44,6 +48,8 @@ pub struct Wifi {
     pub device: WifiDevice<'static, WifiStaDevice>,
     pub controller: WifiController<'static>,
     pub seed: u64,
+    /// Only truly random while the radio is enabled.
+    pub rng: Rng,
 }
 
 pub type StatsADCInstance = ADC1;
@@ -87,6 +93,11 @@ pub struct Watchdog {
     pub peri: Wdt<TIMG1, Async>,
 }
 
+/// The USB-Serial-JTAG port, to which log output is written as well.
+pub struct Console {
+    pub serial: UsbSerialJtag<'static, Async>,
+}
+
 pub struct Bsp {
     pub i2c_bus: &'static I2cBus,
 
@@ -98,6 +109,7 @@ pub struct Bsp {
     pub power_ext: PowerExt,
     pub usb_pd: Usbpd,
     pub watchdog: Watchdog,
+    pub console: Console,
 
     pub high_prio_spawner: SendSpawner,
 }
@@ -137,6 +149,7 @@ impl Bsp {
                 device: wifi_device,
                 controller: wifi_controller,
                 seed: wifi_seed,
+                rng,
             }
         };
 
@@ -208,6 +221,10 @@ impl Bsp {
             Watchdog { peri: timg1.wdt }
         };
 
+        let console = Console {
+            serial: UsbSerialJtag::new_async(peripherals.USB_DEVICE),
+        };
+
         let mut delay = Delay::new(clocks);
 
         static EXECUTOR: StaticCell<InterruptExecutor<2>> = StaticCell::new();
@@ -229,6 +246,7 @@ impl Bsp {
             power_ext,
             usb_pd,
             watchdog,
+            console,
             high_prio_spawner,
         }
     }
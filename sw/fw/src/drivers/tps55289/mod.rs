@@ -1,7 +1,9 @@
+use core::ops::RangeInclusive;
+
 use derive_more::{Deref, From, Into};
 use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 
-use crate::util::{Millivolts, Nanovolts};
+use crate::util::{Milliamps, Millivolts, Nanovolts};
 
 pub mod ll;
 
@@ -15,14 +17,26 @@ pub enum IntFB {
 }
 
 impl IntFB {
-    pub fn multiply(&self, x: u32) -> u32 {
-        let ratio = match self {
+    /// Ratio in units of 1/10000.
+    fn ratio(&self) -> u32 {
+        match self {
             IntFB::Ratio0_2256 => 2_256,
             IntFB::Ratio0_1128 => 1_128,
             IntFB::Ratio0_0752 => 752,
             IntFB::Ratio0_0564 => 564,
-        };
-        x.checked_mul(ratio).unwrap() / 10_000
+        }
+    }
+
+    pub fn multiply(&self, x: u32) -> u32 {
+        x.checked_mul(self.ratio()).unwrap() / 10_000
+    }
+
+    /// Range of output voltages that can be regulated with this feedback ratio.
+    pub fn output_range(&self) -> RangeInclusive<Millivolts> {
+        let ratio = self.ratio();
+        let min = (VRef::MIN_MILLIVOLTS * 10_000).div_ceil(ratio);
+        let max = VRef::MAX_MILLIVOLTS * 10_000 / ratio;
+        Millivolts(min as u16)..=Millivolts(max as u16)
     }
 }
 
@@ -38,12 +52,20 @@ pub enum OperatingStatus {
 pub struct VRef(u16);
 
 impl VRef {
+    pub const MAX: u16 = 0x7FF;
+
+    /// Lowest and highest reference voltage that can be set, rounded to whole millivolts.
+    pub const MIN_MILLIVOLTS: u32 = 45;
+    pub const MAX_MILLIVOLTS: u32 = 1200;
+
     pub fn into_nanovolts(self) -> Nanovolts {
         Nanovolts(45_000_000 + 564_500 * self.0 as u32)
     }
 
+    /// Saturates to the lowest or highest reference voltage.
     pub fn from_nanovolts(from: Nanovolts) -> Self {
-        Self(((from.0 - 45_000_000) / 564_500) as u16)
+        let value = from.0.saturating_sub(45_000_000) / 564_500;
+        Self(value.min(Self::MAX as u32) as u16)
     }
 
     pub fn from_feedback(target: Millivolts, fb: IntFB) -> Self {
@@ -53,3 +75,22 @@ impl VRef {
         VRef::from_nanovolts(nanovolts)
     }
 }
+
+/// Current limit as written to the `iout_limit` register, in steps of 0.5mV over the sense resistor.
+#[derive(Debug, Deref, From, Into)]
+pub struct IoutLimit(u8);
+
+impl IoutLimit {
+    pub const MAX: u8 = 0x7F;
+
+    /// Highest current limit that can be set with the given sense resistor.
+    pub fn max_current(sense_milliohm: u32) -> Milliamps {
+        Milliamps((Self::MAX as u32 * 500 / sense_milliohm) as u16)
+    }
+
+    /// Saturates to the highest limit.
+    pub fn from_current(current: Milliamps, sense_milliohm: u32) -> Self {
+        let value = current.0 as u32 * sense_milliohm / 500;
+        Self(value.min(Self::MAX as u32) as u8)
+    }
+}
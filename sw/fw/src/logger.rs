@@ -1,12 +1,23 @@
-use core::str::FromStr;
+use core::{cell::RefCell, fmt, str::FromStr};
 
+use critical_section::Mutex;
 use embassy_time::Instant;
+use esp_println::print;
+use heapless::String;
 use log::LevelFilter;
 
 use super::println;
 
 const LOG_TARGETS: Option<&'static str> = option_env!("PSU_LOGTARGETS");
 
+/// Return to the start of the line and erase it.
+const CLEAR_LINE: &str = "\r\u{001B}[2K";
+
+pub const PROMPT_SIZE: usize = 160;
+
+/// Line being edited on the console, if any, which is redrawn after every line printed such that the two do not mix.
+static PROMPT: Mutex<RefCell<String<PROMPT_SIZE>>> = Mutex::new(RefCell::new(String::new()));
+
 pub fn init_logger_from_env() {
     unsafe {
         log::set_logger_racy(&EspLogger).unwrap();
@@ -22,6 +33,46 @@ pub fn init_logger_from_env() {
     }
 }
 
+/// Change the level of messages logged, until rebooted.
+pub fn set_level(level: LevelFilter) {
+    unsafe { log::set_max_level_racy(level) };
+}
+
+/// Print a line, without it being interrupted by other output.
+pub fn print_line(args: fmt::Arguments) {
+    critical_section::with(|cs| {
+        let prompt = PROMPT.borrow_ref(cs);
+        if !prompt.is_empty() {
+            print!("{}", CLEAR_LINE);
+        }
+        println!("{}", args);
+        print!("{}", prompt);
+    })
+}
+
+/// Replace the line being edited, redrawing it.
+pub fn set_prompt(line: &str) {
+    critical_section::with(|cs| {
+        let mut prompt = PROMPT.borrow_ref_mut(cs);
+        prompt.clear();
+        // Truncated when too long, which only affects what is shown.
+        for c in line.chars() {
+            if prompt.push(c).is_err() {
+                break;
+            }
+        }
+        print!("{}{}", CLEAR_LINE, prompt);
+    })
+}
+
+/// Finish the line being edited, leaving it as is.
+pub fn commit_prompt() {
+    critical_section::with(|cs| {
+        PROMPT.borrow_ref_mut(cs).clear();
+        println!();
+    })
+}
+
 struct EspLogger;
 
 impl log::Log for EspLogger {
@@ -62,7 +113,7 @@ impl log::Log for EspLogger {
         let now_ms_sub = now_ms % 1000;
         let now_s = now_ms / 1000;
 
-        println!(
+        print_line(format_args!(
             "{}{:3}.{:03} [{}{}{}{} {}{}{}{}]{} {}",
             DIMMED,
             now_s,
@@ -77,7 +128,7 @@ impl log::Log for EspLogger {
             DIMMED,
             RESET,
             record.args(),
-        );
+        ));
     }
 
     fn flush(&self) {}
@@ -1,38 +1,55 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(not(test))]
 #[allow(unused)]
 mod bsp;
 
+#[cfg(not(test))]
 mod executors;
+#[cfg(not(test))]
 mod logger;
 mod serialnumber;
 mod systems;
+// Parts only used by the hardware systems are unused on the host.
+#[cfg_attr(test, allow(dead_code))]
 mod util;
 
 // We plan on open-sourcing all drivers eventually.
 // Hence allow unused code, which will be useful for the eventual library crates.
+#[cfg(not(test))]
 #[allow(unused)]
 mod drivers;
 
+#[cfg(not(test))]
 use embassy_executor::Spawner;
+#[cfg(not(test))]
 use embassy_time::Timer;
+#[cfg(not(test))]
 use esp_backtrace as _;
 
+#[cfg(not(test))]
 use esp_hal::{peripherals::Peripherals, prelude::*};
+#[cfg(not(test))]
 use esp_println::println;
+#[cfg(not(test))]
 use systems::{
+    console::Console,
     events::Events,
+    net::{Http, Scpi},
     watchdog::{self, Watchdog},
 };
 
+#[cfg(not(test))]
 use crate::{bsp::Bsp, serialnumber::SerialNumber};
 
+#[cfg(not(test))]
 #[doc(hidden)]
 unsafe fn __make_static<T>(t: &mut T) -> &'static mut T {
     ::core::mem::transmute(t)
 }
 
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     let mut executor = executors::thread::Executor::new();
@@ -42,6 +59,7 @@ fn main() -> ! {
     })
 }
 
+#[cfg(not(test))]
 #[embassy_executor::task]
 async fn app(spawner: Spawner) {
     println!("=== SARIF Slakkotron application ===");
@@ -61,9 +79,10 @@ async fn app(spawner: Spawner) {
     // Do USB-PD first thing, because the protocol demands it.
     let usb_pd = systems::usb_pd::Usbpd::init(bsp.usb_pd, &spawner).await;
 
-    let storage = systems::storage::Storage::init().await;
-    let config = systems::config::Config::init(storage, &spawner).await;
+    let storage = systems::storage::Storage::init(&spawner).await;
+    let config = systems::config::Config::init(storage, usb_pd, &spawner).await;
     let record = systems::record::Record::init(storage, &spawner).await;
+    let presets = systems::presets::Presets::init(config, storage, &spawner).await;
 
     // let power_ext = systems::power_ext::PowerExt::init(
     //     bsp.power_ext,
@@ -71,14 +90,31 @@ async fn app(spawner: Spawner) {
     //     record,
     //     config,
     //     watchdog,
+    //     reset_reason,
     //     &bsp.high_prio_spawner,
     // )
     // .await;
 
     // let stats = systems::stats::Stats::init(bsp.stats, power_ext, &spawner);
-    let net = systems::net::Net::init(bsp.wifi, config, watchdog, &spawner).await;
-
-    // Events::init(stats, record, config, net, &spawner).await;
+    let net = systems::net::Net::init(
+        bsp.wifi, config, storage, usb_pd, presets, watchdog, &spawner,
+    )
+    .await;
+
+    // Stats are not available until the power stage is brought up.
+    Events::init(None, record, config, storage, net, reset_reason, &spawner).await;
+    Http::init(net, None, record, &spawner);
+    Scpi::init(net, None, None, &spawner);
+    Console::init(
+        bsp.console,
+        None,
+        record,
+        config,
+        storage,
+        usb_pd,
+        net,
+        &spawner,
+    );
 
     loop {
         watchdog_ticket.feed().await;
@@ -0,0 +1,221 @@
+//! Device configuration, persistent storage, and propagate changes to them.
+
+use derive_more::From;
+use embassy_executor::Spawner;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    mutex::Mutex,
+    pubsub::{PubSubBehavior, WaitResult},
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::HistoryBuffer;
+use serde::{Deserialize, Serialize};
+use static_cell::StaticCell;
+
+use crate::{
+    systems::{
+        power_ext,
+        storage::{self, MaxSize, Storage, StorageEntry, StorageKey},
+        usb_pd::Usbpd,
+    },
+    util::{PubSub, Sub},
+};
+
+mod settings;
+
+pub use settings::{Limits, PowerOn, Rejection, Settings, SettingsBuilder};
+
+const PUSH_PERIOD: Duration = Duration::from_secs(30);
+
+/// Number of most recent changes retrieved from the journal.
+pub const JOURNAL_LATEST: usize = 8;
+
+/// Reason for failing to update the settings.
+#[derive(Debug, From)]
+pub enum UpdateError {
+    Rejected(Rejection),
+    /// The settings were valid, but could not be persisted, hence they were not applied.
+    Storage(storage::Error),
+}
+
+/// Origin of a change to the settings.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Boot,
+    Mqtt,
+    Preset,
+    Http,
+    Scpi,
+    Shell,
+}
+
+impl MaxSize for Source {
+    const MAX_SIZE: usize = 1;
+}
+
+/// Applied change to the settings, as recorded in the journal.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Change {
+    pub old: Settings,
+    pub new: Settings,
+    pub source: Source,
+    pub uptime_secs: u64,
+}
+
+impl MaxSize for Change {
+    const MAX_SIZE: usize = 2 * Settings::MAX_SIZE + Source::MAX_SIZE + u64::MAX_SIZE;
+}
+
+impl StorageEntry for Change {
+    const KEY: StorageKey = StorageKey::ConfigChange;
+}
+
+struct Inner {
+    settings: Settings,
+}
+
+pub struct Config {
+    inner: Mutex<CriticalSectionRawMutex, Inner>,
+    storage: &'static Storage,
+    usbpd: &'static Usbpd,
+    notifier: PubSub<Settings>,
+}
+
+impl Config {
+    pub async fn init(
+        storage: &'static Storage,
+        usbpd: &'static Usbpd,
+        spawner: &Spawner,
+    ) -> &'static Self {
+        let data = match storage.fetch::<Settings>().await {
+            Ok(data) => data.unwrap_or_default(),
+            Err(e) => {
+                // Do not overwrite, such that a later firmware might still make sense of it.
+                log::error!("Failed to fetch settings, using defaults: {:?}", e);
+                Settings::default()
+            }
+        };
+
+        let system = Config {
+            inner: Mutex::new(Inner { settings: data }),
+            storage,
+            usbpd,
+            notifier: PubSub::new(),
+        };
+
+        static SYSTEM: StaticCell<Config> = StaticCell::new();
+        let system = SYSTEM.init(system);
+
+        spawner.must_spawn(push_task(system));
+        spawner.must_spawn(reset_task(system));
+
+        system
+    }
+
+    /// Bounds within which settings are currently accepted.
+    pub async fn limits(&self) -> Limits {
+        power_ext::limits(self.usbpd.contract().await)
+    }
+
+    /// Apply a change to the settings, provided they are still within the current limits, see [Settings::check].
+    ///
+    /// Every change is validated here, whatever its source, such that invalid settings never reach the output.
+    pub async fn update(
+        &self,
+        source: Source,
+        f: impl FnOnce(&mut Settings),
+    ) -> Result<(), UpdateError> {
+        let limits = self.limits().await;
+        {
+            let mut guard = self.inner.lock().await;
+            let old_settings = guard.settings;
+            let mut settings = old_settings;
+            f(&mut settings);
+            settings.check(&old_settings, &limits)?;
+
+            if old_settings != settings {
+                // Only persist and publish if it has changed.
+                self.storage.store(settings).await.inspect_err(|e| {
+                    log::error!("Failed to persist settings: {:?}", e);
+                })?;
+                guard.settings = settings;
+
+                let change = Change {
+                    old: old_settings,
+                    new: guard.settings,
+                    source,
+                    uptime_secs: Instant::now().as_secs(),
+                };
+                if let Err(e) = self.storage.append(&change).await {
+                    log::error!("Failed to journal change: {:?}", e);
+                }
+
+                let publisher = self.notifier.publisher().unwrap();
+                publisher.publish(guard.settings).await; // Await until all consumers had their fill.
+
+                log::info!("Synced data");
+            } else {
+                log::debug!("Data has not changed");
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Fetch the most recent changes from the journal, from oldest to newest.
+    pub async fn journal(&self) -> Result<HistoryBuffer<Change, JOURNAL_LATEST>, storage::Error> {
+        self.storage.latest().await
+    }
+
+    /// Revert to the default settings, without persisting them.
+    async fn restore_defaults(&self) {
+        let mut guard = self.inner.lock().await;
+        guard.settings = Settings::default();
+        self.notifier.publish_immediate(guard.settings);
+
+        log::info!("Restored defaults");
+    }
+
+    pub async fn fetch(&self) -> Settings {
+        let guard = self.inner.lock().await;
+        guard.settings
+    }
+
+    /// Publish the current settings to all participants, immediately.
+    pub async fn publish_immediate(&self) {
+        let guard = self.inner.lock().await;
+        self.notifier.publish_immediate(guard.settings);
+    }
+
+    pub fn subscriber(&'static self) -> Sub<Settings> {
+        self.notifier.subscriber().unwrap()
+    }
+}
+
+#[embassy_executor::task]
+async fn push_task(system: &'static Config) {
+    let mut subscriber = system.subscriber();
+    loop {
+        // Send the data every so often to consumers.
+        match embassy_futures::select::select(subscriber.next_message(), Timer::after(PUSH_PERIOD))
+            .await
+        {
+            embassy_futures::select::Either::First(_) => {}
+            embassy_futures::select::Either::Second(_) => {
+                let guard = system.inner.lock().await;
+                system.notifier.publish_immediate(guard.settings); // Push immediate; non-critical.
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn reset_task(system: &'static Config) {
+    let mut subscriber = system.storage.subscriber();
+    loop {
+        if let WaitResult::Message(storage::Event::FactoryReset) = subscriber.next_message().await {
+            system.restore_defaults().await;
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Settings of the output, and the limits they are validated against.
+
+use core::ops::RangeInclusive;
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    systems::storage::{migrate_from, MaxSize, SerializationError, StorageEntry, StorageKey},
+    util::{Milliamps, Millivolts},
+};
+
+const BACKOFF_MS: RangeInclusive<u16> = 100..=60_000;
+
+/// State of the output after powering on.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerOn {
+    /// Always keep the output disabled.
+    #[default]
+    Off,
+    /// Restore the output as it was, unless reset due to a fault like a watchdog reset or brown-out.
+    Restore,
+    /// Always enable the output.
+    On,
+}
+
+impl MaxSize for PowerOn {
+    const MAX_SIZE: usize = 1;
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Copy, Builder)]
+#[builder(no_std, build_fn(error(validation_error = false)))]
+#[builder(derive(Deserialize))]
+pub struct Settings {
+    pub vout_mv: Millivolts,
+    pub iout_ma: Milliamps,
+    pub backoff_ms: u16,
+    pub output_enabled: bool,
+    pub power_on: PowerOn,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            vout_mv: Millivolts(9000),
+            iout_ma: Milliamps(500),
+            backoff_ms: 500,
+            output_enabled: false,
+            power_on: PowerOn::default(),
+        }
+    }
+}
+
+/// Settings as stored before the introduction of the power-on policy.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct SettingsV0 {
+    vout_mv: Millivolts,
+    iout_ma: Milliamps,
+    backoff_ms: u16,
+}
+
+impl From<SettingsV0> for Settings {
+    fn from(value: SettingsV0) -> Self {
+        Self {
+            vout_mv: value.vout_mv,
+            iout_ma: value.iout_ma,
+            backoff_ms: value.backoff_ms,
+            ..Default::default()
+        }
+    }
+}
+
+impl MaxSize for SettingsV0 {
+    const MAX_SIZE: usize = Millivolts::MAX_SIZE + Milliamps::MAX_SIZE + u16::MAX_SIZE;
+}
+
+impl StorageEntry for SettingsV0 {
+    const KEY: StorageKey = StorageKey::ConfigSettings;
+}
+
+impl Settings {
+    pub fn integrate(&mut self, value: SettingsBuilder) {
+        if let Some(vout_mv) = value.vout_mv {
+            self.vout_mv = vout_mv;
+        }
+        if let Some(iout_ma) = value.iout_ma {
+            self.iout_ma = iout_ma;
+        }
+        if let Some(backoff_ms) = value.backoff_ms {
+            self.backoff_ms = backoff_ms;
+        }
+        if let Some(output_enabled) = value.output_enabled {
+            self.output_enabled = output_enabled;
+        }
+        if let Some(power_on) = value.power_on {
+            self.power_on = power_on;
+        }
+    }
+
+    /// Take the output levels of these settings, keeping whether the output is enabled and the power-on policy of `current`.
+    pub fn apply_to(self, current: Settings) -> Settings {
+        Settings {
+            output_enabled: current.output_enabled,
+            power_on: current.power_on,
+            ..self
+        }
+    }
+}
+
+/// Bounds within which settings are accepted.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub vout: RangeInclusive<Millivolts>,
+    pub iout_max: Milliamps,
+    /// Power available from the input supply, if known.
+    pub power_max_mw: Option<u32>,
+}
+
+/// Reason for rejecting an update of the settings.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Rejection {
+    VoutOutOfRange {
+        min_mv: Millivolts,
+        max_mv: Millivolts,
+    },
+    IoutOutOfRange {
+        max_ma: Milliamps,
+    },
+    PowerExceedsContract {
+        max_mw: u32,
+    },
+    BackoffOutOfRange {
+        min_ms: u16,
+        max_ms: u16,
+    },
+}
+
+impl Settings {
+    pub fn validate(&self, limits: &Limits) -> Result<(), Rejection> {
+        if !limits.vout.contains(&self.vout_mv) {
+            return Err(Rejection::VoutOutOfRange {
+                min_mv: *limits.vout.start(),
+                max_mv: *limits.vout.end(),
+            });
+        }
+        if self.iout_ma > limits.iout_max {
+            return Err(Rejection::IoutOutOfRange {
+                max_ma: limits.iout_max,
+            });
+        }
+        if let Some(max_mw) = limits.power_max_mw {
+            let power_mw = self.vout_mv.0 as u32 * self.iout_ma.0 as u32 / 1000;
+            if power_mw > max_mw {
+                return Err(Rejection::PowerExceedsContract { max_mw });
+            }
+        }
+        if !BACKOFF_MS.contains(&self.backoff_ms) {
+            return Err(Rejection::BackoffOutOfRange {
+                min_ms: *BACKOFF_MS.start(),
+                max_ms: *BACKOFF_MS.end(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Validate these settings as replacing `old`.
+    ///
+    /// Settings with the output off are always accepted, such that it can be turned off whatever the limits are.
+    /// As levels are then not validated, they are once the output is turned on.
+    pub fn check(&self, old: &Settings, limits: &Limits) -> Result<(), Rejection> {
+        let levels_changed = self.apply_to(*old) != *old;
+        if !self.output_enabled || (old.output_enabled && !levels_changed) {
+            return Ok(());
+        }
+        self.validate(limits)
+    }
+}
+
+impl MaxSize for Settings {
+    const MAX_SIZE: usize = SettingsV0::MAX_SIZE + bool::MAX_SIZE + PowerOn::MAX_SIZE;
+}
+
+impl StorageEntry for Settings {
+    const KEY: StorageKey = StorageKey::ConfigSettings;
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, buffer: &[u8]) -> Result<Self, SerializationError> {
+        migrate_from::<SettingsV0, _>(version, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            vout: Millivolts(5000)..=Millivolts(20000),
+            iout_max: Milliamps(3000),
+            power_max_mw: Some(15_000),
+        }
+    }
+
+    fn enabled() -> Settings {
+        Settings {
+            output_enabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// Settings that were valid, but exceed the limits after they dropped.
+    fn exceeding() -> Settings {
+        Settings {
+            vout_mv: Millivolts(20000),
+            iout_ma: Milliamps(3000),
+            ..enabled()
+        }
+    }
+
+    #[test]
+    fn rejects_levels_beyond_limits() {
+        let old = enabled();
+        let new = Settings {
+            vout_mv: Millivolts(21000),
+            ..old
+        };
+        assert_eq!(
+            new.check(&old, &limits()),
+            Err(Rejection::VoutOutOfRange {
+                min_mv: Millivolts(5000),
+                max_mv: Millivolts(20000),
+            })
+        );
+        assert_eq!(
+            exceeding().check(&old, &limits()),
+            Err(Rejection::PowerExceedsContract { max_mw: 15_000 })
+        );
+    }
+
+    #[test]
+    fn turns_off_beyond_limits() {
+        let old = exceeding();
+        let new = Settings {
+            output_enabled: false,
+            ..old
+        };
+        assert_eq!(new.check(&old, &limits()), Ok(()));
+    }
+
+    #[test]
+    fn changes_power_on_beyond_limits() {
+        let old = exceeding();
+        let new = Settings {
+            power_on: PowerOn::Restore,
+            ..old
+        };
+        assert_eq!(new.check(&old, &limits()), Ok(()));
+    }
+
+    #[test]
+    fn validates_levels_when_turned_on() {
+        // Levels are not validated while the output is off.
+        let old = Settings {
+            output_enabled: false,
+            ..enabled()
+        };
+        let off = Settings {
+            output_enabled: false,
+            ..exceeding()
+        };
+        assert_eq!(off.check(&old, &limits()), Ok(()));
+
+        let on = Settings {
+            output_enabled: true,
+            ..off
+        };
+        assert!(on.check(&off, &limits()).is_err());
+    }
+}
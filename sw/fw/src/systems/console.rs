@@ -0,0 +1,238 @@
+//! Line-based shell on the USB-Serial-JTAG port, being a way to recover the device when networking is broken.
+//!
+//! Log output is written to the same port, redrawing the line being edited after every logged line.
+
+use core::str::FromStr;
+
+use embassy_executor::Spawner;
+use embedded_io_async::Read;
+use esp_hal::{usb_serial_jtag::UsbSerialJtagRx, Async};
+use heapless::String;
+use log::LevelFilter;
+
+use crate::{
+    bsp, logger,
+    systems::{
+        config::{Config, SettingsBuilder, Source, UpdateError},
+        net::{Net, WifiCredentials},
+        record::Record,
+        stats::Stats,
+        storage::Storage,
+        usb_pd::Usbpd,
+    },
+};
+
+const PROMPT: &str = "> ";
+const LINE_SIZE: usize = logger::PROMPT_SIZE - PROMPT.len();
+
+const HELP: &str = "\
+help                        Show this help
+stats                       Show the latest measurements
+config                      Show the settings
+config <json>               Change the settings, like config {\"vout_mv\":5000}
+usbpd                       Show the USB-PD contract, state and RDO
+wifi                        Show the WiFi network and broker connection
+wifi <ssid> <password>      Provision WiFi credentials, where the SSID may contain spaces
+record                      Show the record of overcurrent events
+journal                     Show the latest changes to the settings
+log [off|error|warn|info|debug|trace]
+                            Show or change the log level until rebooted
+reboot                      Reboot the device
+factory_reset               Erase all settings and reboot";
+
+/// Print a line of output of a command.
+macro_rules! out {
+    ($($arg:tt)*) => {
+        logger::print_line(format_args!($($arg)*))
+    };
+}
+
+pub struct Console;
+
+impl Console {
+    /// Stats are optional, as they depend on the power stage, which is not always fitted.
+    #[allow(clippy::too_many_arguments)]
+    pub fn init(
+        bsp: bsp::Console,
+        stats: Option<&'static Stats>,
+        record: &'static Record,
+        config: &'static Config,
+        storage: &'static Storage,
+        usbpd: &'static Usbpd,
+        net: &'static Net,
+        spawner: &Spawner,
+    ) {
+        // Output is written through the logger instead, such that it does not mix with logged lines.
+        let (_, rx) = bsp.serial.split();
+
+        let shell = Shell {
+            stats,
+            record,
+            config,
+            storage,
+            usbpd,
+            net,
+        };
+        spawner.must_spawn(console_task(shell, rx));
+    }
+}
+
+struct Shell {
+    stats: Option<&'static Stats>,
+    record: &'static Record,
+    config: &'static Config,
+    storage: &'static Storage,
+    usbpd: &'static Usbpd,
+    net: &'static Net,
+}
+
+impl Shell {
+    async fn execute(&self, line: &str) {
+        let (command, argument) = match line.split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+
+        match (command, argument) {
+            ("help", "") => out!("{}", HELP),
+            ("stats", "") => match self.stats {
+                Some(stats) => match stats.latest_data().await {
+                    Some(data) => out!("{:#?}", data),
+                    None => out!("No measurements were taken yet"),
+                },
+                None => out!("No measurements are taken"),
+            },
+            ("config", "") => out!("{:#?}", self.config.fetch().await),
+            ("config", json) => {
+                let Ok((new_settings, _)) = serde_json_core::from_str::<SettingsBuilder>(json)
+                else {
+                    out!("Malformed settings");
+                    return;
+                };
+                match self
+                    .config
+                    .update(Source::Shell, |settings| settings.integrate(new_settings))
+                    .await
+                {
+                    Ok(()) => out!("{:#?}", self.config.fetch().await),
+                    Err(UpdateError::Rejected(rejection)) => out!("Rejected: {:?}", rejection),
+                    Err(UpdateError::Storage(e)) => out!("Failed to persist: {:?}", e),
+                }
+            }
+            ("usbpd", "") => {
+                out!("Contract: {:?}", self.usbpd.contract().await);
+                match self.usbpd.status().await {
+                    Ok((state, rdo)) => {
+                        out!("State: {:?}", state);
+                        out!("RDO: {:?}", rdo);
+                    }
+                    Err(e) => out!("Failed to read status: {:?}", e),
+                }
+            }
+            ("wifi", "") => {
+                match self.net.wifi_ssid().await {
+                    Some(ssid) => out!("Network: {}", ssid),
+                    None => out!("No credentials provisioned"),
+                }
+                out!("{:#?}", self.net.connection().await);
+            }
+            ("wifi", argument) => {
+                let credentials = argument.rsplit_once(' ').and_then(|(ssid, password)| {
+                    Some(WifiCredentials {
+                        ssid: ssid.trim().try_into().ok()?,
+                        password: password.try_into().ok()?,
+                    })
+                });
+                let Some(credentials) = credentials else {
+                    out!("Expected an SSID of at most 32 and a password of at most 64 characters");
+                    return;
+                };
+                match self.net.provision_wifi(credentials).await {
+                    Ok(()) => out!("Provisioned, reconnecting"),
+                    Err(e) => out!("Failed to provision: {:?}", e),
+                }
+            }
+            ("record", "") => out!("{:#?}", self.record.fetch().await),
+            ("journal", "") => match self.config.journal().await {
+                Ok(changes) => {
+                    for change in changes.oldest_ordered() {
+                        out!("{:?}", change);
+                    }
+                }
+                Err(e) => out!("Failed to fetch journal: {:?}", e),
+            },
+            ("log", "") => out!("{}", log::max_level()),
+            ("log", level) => match LevelFilter::from_str(level) {
+                Ok(level) => {
+                    logger::set_level(level);
+                    out!("{}", level);
+                }
+                Err(_) => out!("Unknown log level \"{}\"", level),
+            },
+            ("reboot", "") => esp_hal::reset::software_reset(),
+            ("factory_reset", "") => match self.storage.factory_reset().await {
+                // Rebooted such that no stale settings are persisted again.
+                Ok(()) => esp_hal::reset::software_reset(),
+                Err(e) => out!("Failed to factory reset: {:?}", e),
+            },
+            _ => out!("Unknown command \"{}\", see help", line),
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn console_task(shell: Shell, mut rx: UsbSerialJtagRx<'static, Async>) {
+    let mut line: String<LINE_SIZE> = String::new();
+    let mut buffer = [0; 16];
+    let mut previous = 0;
+    // Whether an escape sequence, like those of the arrow keys, is being skipped.
+    let mut escaping = false;
+
+    loop {
+        let mut prompt: String<{ logger::PROMPT_SIZE }> = String::new();
+        // Note(unwrap): sized to fit both.
+        prompt.push_str(PROMPT).unwrap();
+        prompt.push_str(&line).unwrap();
+        logger::set_prompt(&prompt);
+
+        let read = match rx.read(&mut buffer).await {
+            Ok(read) => read,
+            Err(e) => {
+                log::warn!("Failed to read from console: {:?}", e);
+                continue;
+            }
+        };
+
+        for &byte in &buffer[..read] {
+            match byte {
+                // Escape sequences end with a letter or a tilde.
+                _ if escaping => escaping = !(byte.is_ascii_alphabetic() || byte == b'~'),
+                0x1B => escaping = true,
+                // Terminals send either or both on enter.
+                b'\n' if previous == b'\r' => {}
+                b'\r' | b'\n' => {
+                    logger::commit_prompt();
+                    if !line.trim().is_empty() {
+                        shell.execute(line.trim()).await;
+                    }
+                    line.clear();
+                }
+                // Backspace or delete.
+                0x08 | 0x7F => {
+                    line.pop();
+                }
+                // Ctrl-C discards the line.
+                0x03 => {
+                    logger::commit_prompt();
+                    line.clear();
+                }
+                b' '..=b'~' => {
+                    // Ignored when the line is full.
+                    let _ = line.push(byte as char);
+                }
+                _ => {}
+            }
+            previous = byte;
+        }
+    }
+}
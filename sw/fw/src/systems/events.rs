@@ -1,32 +1,74 @@
 //! System to act on various events and engaging systems correspondingly.
 
+use core::fmt::Write;
+
 use embassy_executor::Spawner;
+use embassy_time::Instant;
+use esp_hal::rtc_cntl::SocResetReason;
+use heapless::String;
+use serde::Serialize;
 
 use crate::systems::{
     config::Config,
     net::{self, Net},
     record::Record,
     stats::Stats,
+    storage::{self, Storage},
 };
 
 pub struct Events;
 
+type ResetReason = String<24>;
+
+/// Condition of this device that requires attention.
+#[derive(Serialize)]
+#[serde(tag = "warning", rename_all = "snake_case")]
+enum Warning {
+    /// The flash is wearing out, see the storage health for details.
+    FlashWear,
+}
+
+/// Snapshot of the state of this device, retained such that it is available while disconnected.
+#[derive(Serialize)]
+struct Status<'a> {
+    identity: net::IdentityReport<'a>,
+    reset_reason: Option<ResetReason>,
+    uptime_secs: u64,
+    connection: net::ConnectionReport,
+}
+
 impl Events {
+    /// Stats are optional, as they depend on the power stage, which is not always fitted.
     pub async fn init(
-        stats: &'static Stats,
+        stats: Option<&'static Stats>,
         record: &'static Record,
         config: &'static Config,
+        storage: &'static Storage,
         net: &'static Net,
+        reset_reason: Option<SocResetReason>,
         spawner: &Spawner,
     ) {
-        spawner.must_spawn(net_task(record, config, net));
-        spawner.must_spawn(publish_task(stats, record, config, net));
+        let reset_reason = reset_reason.and_then(|reset_reason| {
+            let mut str = ResetReason::new();
+            write!(str, "{:?}", reset_reason).ok()?;
+            Some(str)
+        });
+
+        spawner.must_spawn(net_task(record, config, storage, net, reset_reason));
+        spawner.must_spawn(publish_task(stats, record, config, storage, net));
+        spawner.must_spawn(storage_task(storage, net));
     }
 }
 
 /// Task to act on Net events like connected and specific messages received.
 #[embassy_executor::task]
-async fn net_task(record: &'static Record, config: &'static Config, net: &'static Net) {
+async fn net_task(
+    record: &'static Record,
+    config: &'static Config,
+    storage: &'static Storage,
+    net: &'static Net,
+    reset_reason: Option<ResetReason>,
+) {
     let mut subscriber = net.event_subscriber();
     loop {
         use embassy_sync::pubsub::WaitResult;
@@ -37,8 +79,23 @@ async fn net_task(record: &'static Record, config: &'static Config, net: &'stati
                 log::info!("Net {:#?}", event);
                 match event {
                     net::Event::ConnectedMQTT => {
+                        let identity = net.identity().await;
+                        let status = Status {
+                            identity: identity.report(),
+                            reset_reason: reset_reason.clone(),
+                            uptime_secs: Instant::now().as_secs(),
+                            connection: net.connection().await,
+                        };
+                        net.send(
+                            net::Message::new(&net::Topic::Status, &status)
+                                .unwrap()
+                                .retained(),
+                        )
+                        .await;
+
                         record.publish_immediate().await;
                         config.publish_immediate().await;
+                        storage.publish_health().await;
                     }
                     _ => {}
                 }
@@ -51,41 +108,68 @@ async fn net_task(record: &'static Record, config: &'static Config, net: &'stati
 /// Task to publish various reports from systems to Net::MQTT.
 #[embassy_executor::task]
 async fn publish_task(
-    stats: &'static Stats,
+    stats: Option<&'static Stats>,
     record: &'static Record,
     config: &'static Config,
+    storage: &'static Storage,
     net: &'static Net,
 ) {
-    let mut stats_subscriber = stats.subscriber();
+    let mut stats_subscriber = stats.map(|stats| stats.subscriber());
     let mut record_subscriber = record.subscriber();
     let mut config_subscriber = config.subscriber();
+    let mut health_subscriber = storage.health_subscriber();
     loop {
-        use embassy_futures::select::Either3;
+        use embassy_futures::select::Either4;
         use embassy_sync::pubsub::WaitResult;
 
-        match embassy_futures::select::select3(
-            stats_subscriber.next_message(),
+        match embassy_futures::select::select4(
+            async {
+                match stats_subscriber.as_mut() {
+                    Some(subscriber) => subscriber.next_message().await,
+                    None => core::future::pending().await,
+                }
+            },
             record_subscriber.next_message(),
             config_subscriber.next_message(),
+            health_subscriber.next_message(),
         )
         .await
         {
-            Either3::First(WaitResult::Message(message)) => {
+            Either4::First(WaitResult::Message(message)) => {
                 log::info!("Stats {:#?}", message);
                 net.send(net::Message::new(&net::Topic::Stats, &message).unwrap())
                     .await;
             }
-            Either3::Second(WaitResult::Message(message)) => {
+            Either4::Second(WaitResult::Message(message)) => {
                 log::info!("Record {:#?}", message);
                 net.send(net::Message::new(&net::Topic::Record, &message).unwrap())
                     .await;
             }
-            Either3::Third(WaitResult::Message(message)) => {
+            Either4::Third(WaitResult::Message(message)) => {
                 log::info!("Config {:#?}", message);
                 net.send(net::Message::new(&net::Topic::Config, &message).unwrap())
                     .await;
             }
+            Either4::Fourth(WaitResult::Message(message)) => {
+                log::info!("Storage {:#?}", message);
+                net.send(net::Message::new(&net::Topic::StorageHealth, &message).unwrap())
+                    .await;
+            }
             _ => {}
         }
     }
 }
+
+/// Task to forward warnings of Storage to Net::MQTT.
+#[embassy_executor::task]
+async fn storage_task(storage: &'static Storage, net: &'static Net) {
+    let mut subscriber = storage.subscriber();
+    loop {
+        use embassy_sync::pubsub::WaitResult;
+
+        if let WaitResult::Message(storage::Event::WearWarning) = subscriber.next_message().await {
+            net.send(net::Message::new(&net::Topic::Warning, &Warning::FlashWear).unwrap())
+                .await;
+        }
+    }
+}
@@ -1,9 +1,45 @@
+#[cfg(not(test))]
 pub mod config;
+#[cfg(not(test))]
+pub mod console;
+#[cfg(not(test))]
 pub mod events;
+#[cfg(not(test))]
 pub mod net;
+// Only the settings of Config are tested on the host.
+#[cfg(test)]
+mod config {
+    #[allow(dead_code)]
+    mod settings;
+}
+// Only the parts of Net that do not depend on the hardware are tested on the host.
+#[cfg(test)]
+#[allow(dead_code)]
+mod net {
+    mod connection;
+    mod identity;
+    mod mdns {
+        mod packet;
+    }
+    mod message;
+    mod outbox;
+    mod router;
+    mod scpi {
+        mod parser;
+    }
+}
+#[cfg(not(test))]
 pub mod power_ext;
+#[cfg(not(test))]
+pub mod presets;
+#[cfg(not(test))]
 pub mod record;
+#[cfg(not(test))]
 pub mod stats;
+// Partitions, tasks and notifications are only used by the other systems.
+#[cfg_attr(test, allow(dead_code))]
 pub mod storage;
+#[cfg(not(test))]
 pub mod usb_pd;
+#[cfg(not(test))]
 pub mod watchdog;
@@ -0,0 +1,91 @@
+//! Which MQTT brokers to connect to, and how.
+
+use embassy_net::{dns, IpAddress, Stack};
+use esp_wifi::wifi::{WifiDevice, WifiStaDevice};
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::systems::storage::{MaxSize, StorageEntry, StorageKey};
+
+use super::identity;
+
+/// Defaults to use until other settings have been provisioned.
+const DEFAULT_HOST: Option<&str> = option_env!("PSU_MQTT_HOST");
+const DEFAULT_PORT: Option<&str> = option_env!("PSU_MQTT_PORT");
+const DEFAULT_USERNAME: Option<&str> = option_env!("PSU_MQTT_USERNAME");
+const DEFAULT_PASSWORD: Option<&str> = option_env!("PSU_MQTT_PASSWORD");
+/// Set to `1` to connect to the default broker without TLS.
+const DEFAULT_PLAINTEXT: Option<&str> = option_env!("PSU_MQTT_PLAINTEXT");
+
+const TLS_PORT: u16 = 8883;
+const PLAINTEXT_PORT: u16 = 1883;
+
+pub const MAX_BROKERS: usize = 3;
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Broker {
+    /// Hostname or IPv4 address.
+    pub host: String<64>,
+    pub port: u16,
+    /// Connect without TLS, which has to be opted into explicitly.
+    #[serde(default)]
+    pub plaintext: bool,
+}
+
+impl MaxSize for Broker {
+    const MAX_SIZE: usize = String::<64>::MAX_SIZE + u16::MAX_SIZE + bool::MAX_SIZE;
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct BrokerSettings {
+    /// Brokers in order of preference, falling back to the next one when unreachable.
+    pub brokers: Vec<Broker, MAX_BROKERS>,
+    pub client_id: String<23>,
+    pub keepalive_secs: u16,
+    pub username: Option<String<32>>,
+    pub password: Option<String<64>>,
+}
+
+impl MaxSize for BrokerSettings {
+    const MAX_SIZE: usize = Vec::<Broker, MAX_BROKERS>::MAX_SIZE
+        + String::<23>::MAX_SIZE
+        + u16::MAX_SIZE
+        + Option::<String<32>>::MAX_SIZE
+        + Option::<String<64>>::MAX_SIZE;
+}
+
+impl StorageEntry for BrokerSettings {
+    const KEY: StorageKey = StorageKey::BrokerSettings;
+}
+
+impl Default for BrokerSettings {
+    fn default() -> Self {
+        let plaintext = matches!(DEFAULT_PLAINTEXT, Some("1"));
+        let broker = Broker {
+            host: DEFAULT_HOST
+                .and_then(|host| host.try_into().ok())
+                .unwrap_or_else(|| "192.168.1.2".try_into().unwrap()),
+            port: DEFAULT_PORT
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(if plaintext { PLAINTEXT_PORT } else { TLS_PORT }),
+            plaintext,
+        };
+
+        Self {
+            brokers: Vec::from_slice(&[broker]).unwrap(),
+            client_id: identity::client_id(),
+            keepalive_secs: 60,
+            username: DEFAULT_USERNAME.and_then(|username| username.try_into().ok()),
+            password: DEFAULT_PASSWORD.and_then(|password| password.try_into().ok()),
+        }
+    }
+}
+
+/// Resolve the address of a broker, which might already be an IP address.
+pub async fn resolve(
+    stack: &Stack<WifiDevice<'static, WifiStaDevice>>,
+    broker: &Broker,
+) -> Result<IpAddress, dns::Error> {
+    let addresses = stack.dns_query(&broker.host, dns::DnsQueryType::A).await?;
+    addresses.first().copied().ok_or(dns::Error::Failed)
+}
@@ -0,0 +1,299 @@
+//! Commands received over MQTT, dispatched to the systems they concern.
+
+use serde::{Deserialize, Serialize};
+
+use crate::systems::{
+    config::{Rejection, SettingsBuilder, Source, UpdateError},
+    presets,
+};
+
+use super::{
+    router::{Captures, Router},
+    tls, BrokerSettings, Identity, Message, Net, Topic, WifiCredentials, COMMAND_TOKEN,
+};
+
+pub const MAX_ROUTES: usize = 12;
+
+/// Handler of a command, routed to by the pattern it was registered with.
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    Config,
+    ConfigGet,
+    OutputSet,
+    FactoryReset,
+    PresetList,
+    /// The action is the last level of the topic.
+    Preset,
+    Journal,
+    Wifi,
+    Broker,
+    /// The certificate or key is the last level of the topic.
+    Tls,
+    Identity,
+}
+
+/// Reason for not executing a command, as replied on [Topic::Error].
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum CommandError {
+    UnknownCommand,
+    Malformed,
+    Unauthorized,
+    Rejected {
+        rejection: Rejection,
+    },
+    Preset {
+        error: presets::Error,
+    },
+    /// The command was valid, but could not be persisted.
+    Storage,
+}
+
+impl From<UpdateError> for CommandError {
+    fn from(error: UpdateError) -> Self {
+        match error {
+            UpdateError::Rejected(rejection) => CommandError::Rejected { rejection },
+            UpdateError::Storage(_) => CommandError::Storage,
+        }
+    }
+}
+
+impl From<presets::Error> for CommandError {
+    fn from(error: presets::Error) -> Self {
+        CommandError::Preset { error }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorReply<'a> {
+    /// Topic of the command, relative to the namespace by which it was addressed.
+    command: &'a str,
+    error: CommandError,
+}
+
+/// Payload of commands that require the [COMMAND_TOKEN].
+#[derive(Deserialize)]
+struct Authenticated<'a> {
+    token: &'a str,
+}
+
+/// Payload of the command to provision WiFi credentials, which also requires the [COMMAND_TOKEN].
+#[derive(Deserialize)]
+struct ProvisionWifi<'a> {
+    ssid: &'a str,
+    password: &'a str,
+}
+
+/// Payload of the command to provision a certificate or key for TLS, which also requires the [COMMAND_TOKEN].
+#[derive(Deserialize)]
+struct ProvisionTls<'a> {
+    /// In DER, encoded in hex, or none to revert to the one given at build time.
+    der: Option<&'a str>,
+}
+
+/// Payload of commands that concern a single preset.
+#[derive(Deserialize)]
+struct PresetCommand<'a> {
+    name: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct OutputCommand {
+    pub enabled: bool,
+}
+
+/// Register all commands, relative to the namespace of the device.
+pub fn routes() -> Router<Command, MAX_ROUTES> {
+    let mut router = Router::new();
+    for (pattern, command) in [
+        ("cmd/config", Command::Config),
+        ("cmd/config/get", Command::ConfigGet),
+        ("cmd/output/set", Command::OutputSet),
+        ("cmd/factory_reset", Command::FactoryReset),
+        ("cmd/preset/list", Command::PresetList),
+        ("cmd/preset/+", Command::Preset),
+        ("cmd/journal", Command::Journal),
+        ("cmd/wifi", Command::Wifi),
+        ("cmd/broker", Command::Broker),
+        ("cmd/tls/+", Command::Tls),
+        ("cmd/identity", Command::Identity),
+    ] {
+        // Note(unwrap): the table is sized for all commands.
+        router.register(pattern, command).unwrap();
+    }
+    router
+}
+
+pub(super) fn parse<'a, T: Deserialize<'a>>(buf: &'a [u8]) -> Result<T, CommandError> {
+    serde_json_core::from_slice::<T>(buf)
+        .map(|(value, _)| value)
+        .map_err(|_| CommandError::Malformed)
+}
+
+/// Check whether a command carries the [COMMAND_TOKEN].
+pub(super) fn authenticate(buf: &[u8]) -> Result<(), CommandError> {
+    match parse::<Authenticated>(buf) {
+        Ok(command) if is_command_token(command.token) => Ok(()),
+        _ => Err(CommandError::Unauthorized),
+    }
+}
+
+/// Whether `token` is the [COMMAND_TOKEN], which never matches when not set.
+pub(super) fn is_command_token(token: &str) -> bool {
+    COMMAND_TOKEN.is_some_and(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+/// Compare without bailing at the first difference, such that the time taken does not reveal how much of a guess was right.
+///
+/// Only the length of the token can be learned this way.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let difference = a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y));
+    // Keep the compiler from turning the fold into an early exit.
+    core::hint::black_box(difference) == 0
+}
+
+impl Net {
+    /// Act on a received message, yielding a message to send in reply.
+    pub(super) async fn process_message(&self, topic: &str, buf: &[u8]) -> Option<Message> {
+        let identity = self.identity().await;
+        let Some((command, grouped)) = identity.strip(topic) else {
+            log::warn!("Received message from unknown topic \"{}\"", topic);
+            return None;
+        };
+
+        let result = match self.router.route(command) {
+            Some((handler, captures)) => {
+                log::info!("Received {:?}", handler);
+                self.process_command(*handler, &captures, grouped, buf)
+                    .await
+            }
+            None => Err(CommandError::UnknownCommand),
+        };
+
+        match result {
+            Ok(reply) => reply,
+            Err(error) => {
+                log::warn!("Rejected command \"{}\": {:?}", command, error);
+                Message::new(&Topic::Error, &ErrorReply { command, error }).ok()
+            }
+        }
+    }
+
+    async fn process_command(
+        &self,
+        command: Command,
+        captures: &Captures<'_>,
+        grouped: bool,
+        buf: &[u8],
+    ) -> Result<Option<Message>, CommandError> {
+        match command {
+            Command::Config => {
+                let new_settings = parse::<SettingsBuilder>(buf)?;
+                self.config
+                    .update(Source::Mqtt, |settings| settings.integrate(new_settings))
+                    .await?;
+            }
+            Command::ConfigGet => {
+                return Ok(Message::new(&Topic::Config, &self.config.fetch().await).ok());
+            }
+            Command::OutputSet => {
+                let command = parse::<OutputCommand>(buf)?;
+                self.config
+                    .update(Source::Mqtt, |settings| {
+                        settings.output_enabled = command.enabled
+                    })
+                    .await?;
+            }
+            Command::FactoryReset => {
+                authenticate(buf)?;
+                self.storage.factory_reset().await.map_err(|e| {
+                    log::error!("Failed to factory reset: {:?}", e);
+                    CommandError::Storage
+                })?;
+            }
+            Command::PresetList => {
+                return Ok(Message::new(&Topic::Presets, &self.presets.list().await).ok());
+            }
+            Command::Preset => {
+                let command = parse::<PresetCommand>(buf)?;
+                match (captures.level(0), command.name) {
+                    (Some("save"), Some(name)) => self.presets.save(name).await?,
+                    (Some("recall"), Some(name)) => self.presets.recall(name).await?,
+                    // Without a name, the boot default is cleared.
+                    (Some("default"), name) => self.presets.set_boot_default(name).await?,
+                    (Some("save" | "recall"), None) => return Err(CommandError::Malformed),
+                    _ => return Err(CommandError::UnknownCommand),
+                }
+            }
+            Command::Journal => {
+                // Sent by a separate task, as every change is sent as a separate message.
+                // Ignored when a request is still pending.
+                let _ = self.journal_channel.try_send(());
+            }
+            Command::Wifi => {
+                authenticate(buf)?;
+                let command = parse::<ProvisionWifi>(buf)?;
+                let credentials = WifiCredentials {
+                    ssid: command
+                        .ssid
+                        .try_into()
+                        .map_err(|_| CommandError::Malformed)?,
+                    password: command
+                        .password
+                        .try_into()
+                        .map_err(|_| CommandError::Malformed)?,
+                };
+                self.provision_wifi(credentials).await.map_err(|e| {
+                    log::error!("Failed to provision WiFi credentials: {:?}", e);
+                    CommandError::Storage
+                })?;
+            }
+            Command::Broker => {
+                authenticate(buf)?;
+                let settings = parse::<BrokerSettings>(buf)?;
+                self.provision_broker(settings).await.map_err(|e| {
+                    log::error!("Failed to provision broker settings: {:?}", e);
+                    CommandError::Storage
+                })?;
+            }
+            Command::Tls => {
+                authenticate(buf)?;
+                let slot = captures
+                    .level(0)
+                    .and_then(tls::Slot::from_level)
+                    .ok_or(CommandError::UnknownCommand)?;
+                let command = parse::<ProvisionTls>(buf)?;
+                let der = match command.der {
+                    Some(hex) => Some(tls::decode(hex).ok_or(CommandError::Malformed)?),
+                    None => None,
+                };
+                self.provision_tls(slot, der).await.map_err(|e| {
+                    log::error!("Failed to provision {:?}: {:?}", slot, e);
+                    CommandError::Storage
+                })?;
+            }
+            Command::Identity => {
+                // Identities are specific to a single device.
+                if grouped {
+                    return Err(CommandError::UnknownCommand);
+                }
+
+                authenticate(buf)?;
+                let identity = parse::<Identity>(buf)?;
+                if !identity.is_valid() {
+                    return Err(CommandError::Malformed);
+                }
+                self.assign_identity(identity.clone()).await.map_err(|e| {
+                    log::error!("Failed to assign identity: {:?}", e);
+                    CommandError::Storage
+                })?;
+                return Ok(Message::new(&Topic::Identity, &identity.report()).ok());
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,263 @@
+//! State of the connection to the broker, and when to retry connecting.
+
+use embassy_time::{Duration, Instant};
+use rand_core::RngCore;
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use serde::Serialize;
+
+/// Delay after the first pass over all brokers failed, doubling with every subsequent pass.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Sessions shorter than this do not count as having connected successfully, preventing a tight reconnect loop.
+const MIN_SESSION: Duration = Duration::from_secs(30);
+
+/// Why connecting to a broker failed.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Failure {
+    Dns,
+    Socket,
+    NoCaCertificate,
+    Tls,
+    /// Refused by the broker, with the reason code of MQTT v5.
+    Refused {
+        code: u8,
+    },
+    Network,
+}
+
+impl From<ReasonCode> for Failure {
+    fn from(code: ReasonCode) -> Self {
+        match code {
+            ReasonCode::NetworkError => Failure::Network,
+            code => Failure::Refused { code: code.into() },
+        }
+    }
+}
+
+impl Failure {
+    /// Whether retrying is futile until the settings change, hence should be done only sparingly.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Failure::NoCaCertificate => true,
+            Failure::Refused { code } => matches!(
+                ReasonCode::from(*code),
+                ReasonCode::UnsupportedProtocolVersion
+                    | ReasonCode::ClientIdNotValid
+                    | ReasonCode::BadUserNameOrPassword
+                    | ReasonCode::NotAuthorized
+                    | ReasonCode::Banned
+                    | ReasonCode::BadAuthMethod
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Why an established connection ended.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disconnect {
+    Network,
+    /// Disconnected to apply new settings or subscriptions.
+    Reconfigured,
+}
+
+/// Exponentially growing delay between passes over all brokers.
+#[derive(Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Delay before the next pass, of which half is random such that devices do not reconnect in lockstep.
+    pub fn next(&mut self, permanent: bool, rng: &mut impl RngCore) -> Duration {
+        let ceiling = if permanent {
+            MAX_BACKOFF
+        } else {
+            (INITIAL_BACKOFF * 2u32.pow(self.failures.min(16))).min(MAX_BACKOFF)
+        };
+        self.failures = self.failures.saturating_add(1);
+
+        let half = ceiling.as_millis() / 2;
+        let jitter = rng.next_u64() % (half + 1);
+        Duration::from_millis(half + jitter)
+    }
+
+    /// Account for a session having ended, yielding the delay before reconnecting, if any.
+    pub fn disconnected(
+        &mut self,
+        reason: Disconnect,
+        session: Duration,
+        rng: &mut impl RngCore,
+    ) -> Option<Duration> {
+        if reason == Disconnect::Reconfigured || session >= MIN_SESSION {
+            self.reset();
+            None
+        } else {
+            Some(self.next(false, rng))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Attempts to connect to a broker since booting.
+    attempts: u32,
+    /// Attempts that failed since the last successful one.
+    failures: u32,
+    last_error: Option<Failure>,
+    connected_since: Option<Instant>,
+}
+
+/// Metrics as published.
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct Report {
+    pub attempts: u32,
+    pub failures: u32,
+    pub last_error: Option<Failure>,
+    /// Duration of the current session, if connected.
+    pub session_secs: Option<u64>,
+}
+
+impl Metrics {
+    pub fn attempt(&mut self) {
+        self.attempts = self.attempts.saturating_add(1);
+    }
+
+    pub fn failed(&mut self, failure: Failure) {
+        self.failures = self.failures.saturating_add(1);
+        self.last_error = Some(failure);
+    }
+
+    pub fn connected(&mut self) {
+        self.failures = 0;
+        self.connected_since = Some(Instant::now());
+    }
+
+    /// End the current session, yielding its duration.
+    pub fn disconnected(&mut self) -> Duration {
+        self.connected_since
+            .take()
+            .map(|since| since.elapsed())
+            .unwrap_or_default()
+    }
+
+    pub fn report(&self) -> Report {
+        Report {
+            attempts: self.attempts,
+            failures: self.failures,
+            last_error: self.last_error,
+            session_secs: self.connected_since.map(|since| since.elapsed().as_secs()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Yields the same value over and over, to find the bounds of the jitter.
+    struct Fixed(u64);
+
+    impl RngCore for Fixed {
+        fn next_u32(&mut self) -> u32 {
+            self.0 as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(self.0 as u8);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    /// Delays of the first passes, for the jitter chosen by `rng`.
+    fn delays(rng: &mut Fixed, passes: usize) -> std::vec::Vec<u64> {
+        let mut backoff = Backoff::default();
+        (0..passes)
+            .map(|_| backoff.next(false, rng).as_millis())
+            .collect()
+    }
+
+    #[test]
+    fn doubles_from_initial_backoff() {
+        assert_eq!(delays(&mut Fixed(0), 5), [500, 1_000, 2_000, 4_000, 8_000]);
+    }
+
+    #[test]
+    fn jitters_up_to_ceiling() {
+        for value in [1, 499, 500, 0x1234_5678_9ABC, u64::MAX] {
+            let delays = delays(&mut Fixed(value), 12);
+            for (failures, delay) in delays.into_iter().enumerate() {
+                let ceiling = (INITIAL_BACKOFF * 2u32.pow(failures as u32))
+                    .min(MAX_BACKOFF)
+                    .as_millis();
+                assert!(
+                    delay >= ceiling / 2 && delay <= ceiling,
+                    "{} is not within {}..={}",
+                    delay,
+                    ceiling / 2,
+                    ceiling
+                );
+            }
+        }
+
+        // The ceiling itself is reached as well.
+        assert_eq!(delays(&mut Fixed(500), 1), [1_000]);
+    }
+
+    #[test]
+    fn caps_delay() {
+        let delays = delays(&mut Fixed(0), 40);
+        assert_eq!(delays[39], MAX_BACKOFF.as_millis() / 2);
+    }
+
+    #[test]
+    fn waits_longest_on_permanent_failure() {
+        let mut backoff = Backoff::default();
+        let delay = backoff.next(true, &mut Fixed(0));
+        assert_eq!(delay, MAX_BACKOFF / 2);
+    }
+
+    #[test]
+    fn resets_after_session() {
+        let mut rng = Fixed(0);
+        let mut backoff = Backoff::default();
+        for _ in 0..4 {
+            backoff.next(false, &mut rng);
+        }
+
+        // A short session does not count as connected.
+        let short = MIN_SESSION - Duration::from_secs(1);
+        assert_eq!(
+            backoff.disconnected(Disconnect::Network, short, &mut rng),
+            Some(Duration::from_millis(8_000))
+        );
+
+        assert_eq!(
+            backoff.disconnected(Disconnect::Network, MIN_SESSION, &mut rng),
+            None
+        );
+        assert_eq!(backoff.next(false, &mut rng), Duration::from_millis(500));
+
+        backoff.next(false, &mut rng);
+        assert_eq!(
+            backoff.disconnected(Disconnect::Reconfigured, Duration::from_secs(0), &mut rng),
+            None
+        );
+        assert_eq!(backoff.next(false, &mut rng), Duration::from_millis(500));
+    }
+}
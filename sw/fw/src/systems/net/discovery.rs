@@ -0,0 +1,286 @@
+//! Home Assistant MQTT discovery, announcing the entities of this device.
+//!
+//! Uses the abbreviated keys of Home Assistant to keep the announcements small.
+
+use core::fmt::Write;
+
+use heapless::String;
+use serde::Serialize;
+
+use crate::systems::power_ext;
+
+use super::{
+    identity::{self, Identity, Name, ROOT},
+    Error, Message, TOPIC_SIZE,
+};
+
+const DISCOVERY_PREFIX: &str = "homeassistant";
+pub(super) const MANUFACTURER: &str = "Wassasin";
+pub(super) const MODEL: &str = "Slakkotron";
+pub(super) const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Vout states as serialized, see [power_ext::State].
+const VOUT_STATES: &[&str] = &["disabled", "enabled", "enabling", "ocp"];
+
+type NodeId = String<24>;
+type UniqueId = String<48>;
+
+/// Device block under which all entities are grouped.
+#[derive(Serialize)]
+struct Device {
+    ids: [NodeId; 1],
+    name: Name,
+    mf: &'static str,
+    mdl: &'static str,
+    sw: &'static str,
+}
+
+#[derive(Serialize)]
+struct Entity<'a> {
+    #[serde(skip)]
+    component: &'static str,
+    #[serde(skip)]
+    object_id: &'static str,
+
+    /// Base topic, substituted for `~` in the other topics.
+    #[serde(rename = "~")]
+    base: &'a str,
+    name: &'static str,
+    uniq_id: UniqueId,
+    stat_t: &'static str,
+    val_tpl: &'static str,
+    avty_t: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dev_cla: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stat_cla: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_meas: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ops: Option<&'static [&'static str]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cmd_t: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cmd_tpl: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pl_on: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pl_off: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stat_on: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stat_off: Option<&'static str>,
+    dev: &'a Device,
+}
+
+impl<'a> Entity<'a> {
+    fn new(
+        node: &'a Node,
+        component: &'static str,
+        object_id: &'static str,
+        name: &'static str,
+        stat_t: &'static str,
+        val_tpl: &'static str,
+    ) -> Self {
+        let mut uniq_id = UniqueId::new();
+        // Note(unwrap): object ids are small enough to always fit.
+        write!(uniq_id, "{}_{}", node.id(), object_id).unwrap();
+
+        Self {
+            component,
+            object_id,
+            base: &node.base,
+            name,
+            uniq_id,
+            stat_t,
+            val_tpl,
+            avty_t: "~/availability",
+            dev_cla: None,
+            stat_cla: None,
+            unit_of_meas: None,
+            ops: None,
+            cmd_t: None,
+            cmd_tpl: None,
+            min: None,
+            max: None,
+            pl_on: None,
+            pl_off: None,
+            stat_on: None,
+            stat_off: None,
+            dev: &node.device,
+        }
+    }
+
+    fn voltage(self) -> Self {
+        Self {
+            dev_cla: Some("voltage"),
+            unit_of_meas: Some("mV"),
+            ..self
+        }
+    }
+
+    fn measurement(self) -> Self {
+        Self {
+            stat_cla: Some("measurement"),
+            ..self
+        }
+    }
+
+    fn message(&self) -> Option<Message> {
+        let mut topic = String::new();
+        let message = write!(
+            topic,
+            "{}/{}/{}/{}/config",
+            DISCOVERY_PREFIX, self.component, self.dev.ids[0], self.object_id
+        )
+        .map_err(|_| Error::TopicTooLarge)
+        .and_then(|_| Message::with_topic(topic, self))
+        // Retained, such that Home Assistant picks them up whenever it (re)connects.
+        .map(Message::retained);
+
+        message
+            .inspect_err(|e| log::error!("Failed to announce {}: {:?}", self.object_id, e))
+            .ok()
+    }
+}
+
+/// This device, as known to Home Assistant.
+pub struct Node {
+    base: String<TOPIC_SIZE>,
+    device: Device,
+}
+
+impl Node {
+    pub fn new(identity: &Identity) -> Self {
+        let mut id = NodeId::new();
+        // Note(unwrap): a serial number is always 12 characters.
+        write!(id, "{}_{}", ROOT, identity::serial()).unwrap();
+
+        let name = identity.name.clone().unwrap_or_else(|| {
+            let mut name = Name::new();
+            write!(name, "{} {}", MODEL, identity::serial()).unwrap();
+            name
+        });
+
+        Self {
+            base: identity::device_prefix(),
+            device: Device {
+                ids: [id],
+                name,
+                mf: MANUFACTURER,
+                mdl: MODEL,
+                sw: FIRMWARE_VERSION,
+            },
+        }
+    }
+
+    fn id(&self) -> &str {
+        &self.device.ids[0]
+    }
+
+    /// Discovery configs of all entities of this device, to be published retained.
+    pub fn announcements(&self) -> impl Iterator<Item = Message> + '_ {
+        self.entities()
+            .into_iter()
+            .filter_map(|entity| entity.message())
+    }
+
+    fn entities(&self) -> [Entity<'_>; 7] {
+        let limits = power_ext::limits(None);
+
+        [
+            Entity::new(
+                self,
+                "sensor",
+                "vsupply",
+                "Supply voltage",
+                "~/stats",
+                "{{value_json.vsupply_mv}}",
+            )
+            .voltage()
+            .measurement(),
+            Entity::new(
+                self,
+                "sensor",
+                "vout",
+                "Output voltage",
+                "~/stats",
+                "{{value_json.vout_mv}}",
+            )
+            .voltage()
+            .measurement(),
+            Entity::new(
+                self,
+                "sensor",
+                "vprog",
+                "Programmed voltage",
+                "~/stats",
+                "{{value_json.vprog_mv}}",
+            )
+            .voltage()
+            .measurement(),
+            Entity {
+                dev_cla: Some("enum"),
+                ops: Some(VOUT_STATES),
+                ..Entity::new(
+                    self,
+                    "sensor",
+                    "vout_state",
+                    "Output state",
+                    "~/stats",
+                    "{{value_json.vout_state}}",
+                )
+            },
+            Entity {
+                cmd_t: Some("~/cmd/config"),
+                cmd_tpl: Some("{\"vout_mv\":{{value}}}"),
+                min: Some(limits.vout.start().0),
+                max: Some(limits.vout.end().0),
+                ..Entity::new(
+                    self,
+                    "number",
+                    "vout_set",
+                    "Output voltage setpoint",
+                    "~/config",
+                    "{{value_json.vout_mv}}",
+                )
+                .voltage()
+            },
+            Entity {
+                dev_cla: Some("current"),
+                unit_of_meas: Some("mA"),
+                cmd_t: Some("~/cmd/config"),
+                cmd_tpl: Some("{\"iout_ma\":{{value}}}"),
+                min: Some(0),
+                max: Some(limits.iout_max.0),
+                ..Entity::new(
+                    self,
+                    "number",
+                    "iout_set",
+                    "Output current limit",
+                    "~/config",
+                    "{{value_json.iout_ma}}",
+                )
+            },
+            Entity {
+                cmd_t: Some("~/cmd/output/set"),
+                pl_on: Some("{\"enabled\":true}"),
+                pl_off: Some("{\"enabled\":false}"),
+                stat_on: Some("on"),
+                stat_off: Some("off"),
+                ..Entity::new(
+                    self,
+                    "switch",
+                    "output",
+                    "Output",
+                    "~/config",
+                    "{{'on' if value_json.output_enabled else 'off'}}",
+                )
+            },
+        ]
+    }
+}
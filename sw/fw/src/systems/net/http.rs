@@ -0,0 +1,402 @@
+//! HTTP/1.1 server exposing the state of the device as JSON, for consumers without an MQTT broker.
+//!
+//! Serves a single request per connection, and a control panel for browsers, see [panel].
+//! Changes require the command token, like destructive commands over MQTT, as anyone on the network can connect.
+
+use core::fmt::Write as _;
+
+use embassy_executor::Spawner;
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Write;
+use heapless::{String, Vec};
+use serde::Serialize;
+
+use crate::systems::{
+    config::{Settings, SettingsBuilder, Source},
+    record::Record,
+    stats::Stats,
+};
+
+use super::{
+    commands::{self, CommandError, OutputCommand},
+    mdns::Service,
+    panel, Net, CONTENT_SIZE, SOCKET_BUFFER_SIZE,
+};
+
+pub(super) const PORT: u16 = 80;
+
+/// Number of requests that can be served at the same time, each having its own socket.
+///
+/// Exceeds the number of event streams, such that requests can be served while streaming.
+pub const MAX_CONNECTIONS: usize = panel::MAX_STREAMS + 1;
+
+/// Largest request, including its headers.
+const REQUEST_SIZE: usize = 1024;
+
+/// Time after which an idle connection is dropped.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time in which a request has to be received completely, such that a slow client cannot hold on to a connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Status {
+    Ok,
+    BadRequest,
+    Unauthorized,
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    UnprocessableContent,
+    InternalServerError,
+    ServiceUnavailable,
+}
+
+impl Status {
+    fn line(&self) -> &'static str {
+        match self {
+            Status::Ok => "200 OK",
+            Status::BadRequest => "400 Bad Request",
+            Status::Unauthorized => "401 Unauthorized",
+            Status::NotFound => "404 Not Found",
+            Status::MethodNotAllowed => "405 Method Not Allowed",
+            Status::PayloadTooLarge => "413 Payload Too Large",
+            Status::UnprocessableContent => "422 Unprocessable Content",
+            Status::InternalServerError => "500 Internal Server Error",
+            Status::ServiceUnavailable => "503 Service Unavailable",
+        }
+    }
+}
+
+/// Reason for not serving a request, other than the rejection of a command.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+enum HttpError {
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    /// No measurements were taken yet, or too many clients are streaming events.
+    Unavailable,
+}
+
+impl HttpError {
+    fn status(&self) -> Status {
+        match self {
+            HttpError::BadRequest => Status::BadRequest,
+            HttpError::NotFound => Status::NotFound,
+            HttpError::MethodNotAllowed => Status::MethodNotAllowed,
+            HttpError::PayloadTooLarge => Status::PayloadTooLarge,
+            HttpError::Unavailable => Status::ServiceUnavailable,
+        }
+    }
+}
+
+fn command_status(error: &CommandError) -> Status {
+    match error {
+        CommandError::UnknownCommand => Status::NotFound,
+        CommandError::Malformed => Status::BadRequest,
+        CommandError::Unauthorized => Status::Unauthorized,
+        CommandError::Rejected { .. } | CommandError::Preset { .. } => Status::UnprocessableContent,
+        CommandError::Storage => Status::InternalServerError,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Request<'a> {
+    method: &'a str,
+    /// Path of the target, without the query.
+    path: &'a str,
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request, yielding none when it has not been received completely yet.
+    fn parse(buffer: &'a [u8]) -> Result<Option<Self>, HttpError> {
+        let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") else {
+            return Ok(None);
+        };
+        let head = core::str::from_utf8(&buffer[..end]).map_err(|_| HttpError::BadRequest)?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(version)) = (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) else {
+            return Err(HttpError::BadRequest);
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(HttpError::BadRequest);
+        }
+
+        let mut content_length = 0;
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(HttpError::BadRequest)?;
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| HttpError::BadRequest)?;
+            }
+        }
+
+        let body = &buffer[end + 4..];
+        if body.len() < content_length {
+            return Ok(None);
+        }
+
+        Ok(Some(Request {
+            method,
+            path: target.split('?').next().unwrap_or_default(),
+            body: &body[..content_length],
+        }))
+    }
+}
+
+pub type Json = Vec<u8, CONTENT_SIZE>;
+
+pub fn json(value: &impl Serialize) -> Option<Json> {
+    let mut json = Json::new();
+    json.resize_default(CONTENT_SIZE).unwrap();
+    match serde_json_core::to_slice(value, &mut json) {
+        Ok(size) => {
+            json.truncate(size);
+            Some(json)
+        }
+        Err(e) => {
+            log::error!("Failed to serialize response: {:?}", e);
+            None
+        }
+    }
+}
+
+// A single response exists per connection at any time, hence its size does not matter.
+#[allow(clippy::large_enum_variant)]
+enum Body {
+    Json(Json),
+    /// Content embedded in flash.
+    Static(&'static [u8]),
+}
+
+struct Response {
+    status: Status,
+    content_type: &'static str,
+    body: Body,
+}
+
+impl Response {
+    fn new(status: Status, value: &impl Serialize) -> Self {
+        match json(value) {
+            Some(json) => Self {
+                status,
+                content_type: "application/json",
+                body: Body::Json(json),
+            },
+            None => Self {
+                status: Status::InternalServerError,
+                content_type: "application/json",
+                body: Body::Static(&[]),
+            },
+        }
+    }
+
+    fn page(content: &'static [u8]) -> Self {
+        Self {
+            status: Status::Ok,
+            content_type: "text/html; charset=utf-8",
+            body: Body::Static(content),
+        }
+    }
+
+    fn ok(value: &impl Serialize) -> Self {
+        Self::new(Status::Ok, value)
+    }
+
+    fn error(error: HttpError) -> Self {
+        Self::new(error.status(), &error)
+    }
+
+    fn settings(result: Result<Settings, CommandError>) -> Self {
+        match result {
+            Ok(settings) => Self::ok(&settings),
+            Err(error) => Self::new(command_status(&error), &error),
+        }
+    }
+
+    async fn write_to(&self, socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+        let body = match &self.body {
+            Body::Json(json) => json.as_slice(),
+            Body::Static(content) => content,
+        };
+
+        let mut head: String<160> = String::new();
+        // Note(unwrap): the headers are small enough to always fit.
+        write!(
+            head,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status.line(),
+            self.content_type,
+            body.len()
+        )
+        .unwrap();
+
+        socket.write_all(head.as_bytes()).await?;
+        socket.write_all(body).await?;
+        socket.flush().await
+    }
+}
+
+pub struct Http;
+
+impl Http {
+    /// Without stats, as they depend on the power stage, these are reported as unavailable.
+    pub fn init(
+        net: &'static Net,
+        stats: Option<&'static Stats>,
+        record: &'static Record,
+        spawner: &Spawner,
+    ) {
+        for _ in 0..MAX_CONNECTIONS {
+            spawner.must_spawn(http_task(net, stats, record));
+        }
+        net.services.advertise(Service::Http);
+    }
+}
+
+/// Read a request into `buffer`, up to and including its body, yielding its length.
+async fn receive(
+    socket: &mut TcpSocket<'_>,
+    buffer: &mut [u8],
+) -> Result<Result<usize, HttpError>, tcp::Error> {
+    let mut len = 0;
+    loop {
+        match Request::parse(&buffer[..len]) {
+            Ok(Some(_)) => return Ok(Ok(len)),
+            Ok(None) if len == buffer.len() => return Ok(Err(HttpError::PayloadTooLarge)),
+            Ok(None) => {}
+            Err(e) => return Ok(Err(e)),
+        }
+
+        let read = socket.read(&mut buffer[len..]).await?;
+        if read == 0 {
+            // Closed before the request was complete.
+            return Err(tcp::Error::ConnectionReset);
+        }
+        len += read;
+    }
+}
+
+async fn handle(
+    net: &'static Net,
+    stats: Option<&'static Stats>,
+    record: &'static Record,
+    request: &Request<'_>,
+) -> Response {
+    match (request.method, request.path) {
+        ("GET", "/") => Response::page(panel::PAGE),
+        ("GET", "/power") => Response::ok(&panel::Power::new(net).await),
+        ("GET", "/stats") => match stats {
+            Some(stats) => match stats.latest_data().await {
+                Some(data) => Response::ok(&data),
+                None => Response::error(HttpError::Unavailable),
+            },
+            None => Response::error(HttpError::Unavailable),
+        },
+        ("GET", "/record") => Response::ok(&record.fetch().await),
+        ("GET", "/config") => Response::ok(&net.config.fetch().await),
+        ("PATCH", "/config") => {
+            let result = async {
+                commands::authenticate(request.body)?;
+                let new_settings = commands::parse::<SettingsBuilder>(request.body)?;
+                net.config
+                    .update(Source::Http, |settings| settings.integrate(new_settings))
+                    .await?;
+                Ok(net.config.fetch().await)
+            };
+            Response::settings(result.await)
+        }
+        ("POST", "/output") => {
+            let result = async {
+                commands::authenticate(request.body)?;
+                let command = commands::parse::<OutputCommand>(request.body)?;
+                net.config
+                    .update(Source::Http, |settings| {
+                        settings.output_enabled = command.enabled
+                    })
+                    .await?;
+                Ok(net.config.fetch().await)
+            };
+            Response::settings(result.await)
+        }
+        (_, "/" | "/power" | "/events" | "/stats" | "/record" | "/config" | "/output") => {
+            Response::error(HttpError::MethodNotAllowed)
+        }
+        _ => Response::error(HttpError::NotFound),
+    }
+}
+
+/// Stream events until the client goes away, yielding a response only when no stream is available.
+async fn events(
+    socket: &mut TcpSocket<'_>,
+    net: &'static Net,
+    stats: Option<&'static Stats>,
+    record: &'static Record,
+) -> Option<Response> {
+    let Some(stream) = panel::Stream::open() else {
+        return Some(Response::error(HttpError::Unavailable));
+    };
+
+    if let Err(e) = stream.run(socket, net, stats, record).await {
+        log::info!("Event stream ended: {:?}", e);
+    }
+    None
+}
+
+#[embassy_executor::task(pool_size = MAX_CONNECTIONS)]
+async fn http_task(net: &'static Net, stats: Option<&'static Stats>, record: &'static Record) {
+    let mut rx_buffer = [0; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0; SOCKET_BUFFER_SIZE];
+    let mut buffer = [0; REQUEST_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(net.stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(TIMEOUT));
+
+        if let Err(e) = socket.accept(PORT).await {
+            log::warn!("Failed to accept HTTP connection: {:?}", e);
+            continue;
+        }
+
+        let response = match with_timeout(REQUEST_TIMEOUT, receive(&mut socket, &mut buffer)).await
+        {
+            Ok(Ok(Ok(len))) => {
+                // Note(unwrap): a complete and valid request was received.
+                let request = Request::parse(&buffer[..len]).unwrap().unwrap();
+                log::info!("HTTP {} {}", request.method, request.path);
+
+                if (request.method, request.path) == ("GET", "/events") {
+                    events(&mut socket, net, stats, record).await
+                } else {
+                    Some(handle(net, stats, record, &request).await)
+                }
+            }
+            Ok(Ok(Err(e))) => Some(Response::error(e)),
+            Ok(Err(e)) => {
+                log::warn!("Failed to receive HTTP request: {:?}", e);
+                None
+            }
+            Err(_) => {
+                log::warn!("Timed out receiving HTTP request");
+                None
+            }
+        };
+
+        if let Some(response) = response {
+            if let Err(e) = response.write_to(&mut socket).await {
+                log::warn!("Failed to send HTTP response: {:?}", e);
+            }
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
@@ -0,0 +1,178 @@
+//! How this device is known on the broker, and by which topics it can be addressed.
+
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    serialnumber::SerialNumber,
+    systems::storage::{MaxSize, StorageEntry, StorageKey},
+};
+
+use super::message::TOPIC_SIZE;
+
+/// Root of all topics, below which every device has its own namespace.
+pub const ROOT: &str = "slakkotron";
+
+/// Level below [ROOT] of the namespaces of groups of devices.
+const GROUP_LEVEL: &str = "group";
+
+pub const MAX_GROUPS: usize = 4;
+
+pub type Name = String<32>;
+pub type GroupName = String<16>;
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Identity {
+    /// Name assigned by the user, only for display.
+    pub name: Option<Name>,
+    /// Groups of which this device is a member, each having their own namespace to which it subscribes.
+    #[serde(default)]
+    pub groups: Vec<GroupName, MAX_GROUPS>,
+}
+
+impl MaxSize for Identity {
+    const MAX_SIZE: usize = Option::<Name>::MAX_SIZE + Vec::<GroupName, MAX_GROUPS>::MAX_SIZE;
+}
+
+impl StorageEntry for Identity {
+    const KEY: StorageKey = StorageKey::Identity;
+}
+
+/// Identity as published, along with the serial number.
+#[derive(Serialize)]
+pub struct Report<'a> {
+    pub serial: String<12>,
+    pub name: &'a Option<Name>,
+    pub groups: &'a [GroupName],
+}
+
+impl Identity {
+    /// Group names end up in topics, hence must not contain separators or wildcards.
+    pub fn is_valid(&self) -> bool {
+        self.groups
+            .iter()
+            .all(|group| !group.is_empty() && !group.contains(['/', '+', '#']))
+    }
+
+    pub fn report(&self) -> Report<'_> {
+        Report {
+            serial: serial(),
+            name: &self.name,
+            groups: &self.groups,
+        }
+    }
+
+    /// Namespaces by which this device can be addressed, starting with its own.
+    pub fn prefixes(&self) -> impl Iterator<Item = String<TOPIC_SIZE>> + '_ {
+        core::iter::once(device_prefix()).chain(self.groups.iter().map(|group| group_prefix(group)))
+    }
+
+    /// Strip the namespace by which this device was addressed from `topic`.
+    ///
+    /// Also yields whether the device was addressed as member of a group.
+    pub fn strip<'a>(&self, topic: &'a str) -> Option<(&'a str, bool)> {
+        let topic = topic.strip_prefix(ROOT)?.strip_prefix('/')?;
+
+        if let Some(topic) = topic
+            .strip_prefix(GROUP_LEVEL)
+            .and_then(|topic| topic.strip_prefix('/'))
+        {
+            let (group, rest) = topic.split_once('/')?;
+            return self
+                .groups
+                .iter()
+                .any(|member| member == group)
+                .then_some((rest, true));
+        }
+
+        let (serial_level, rest) = topic.split_once('/')?;
+        (serial_level == serial().as_str()).then_some((rest, false))
+    }
+}
+
+pub fn serial() -> String<12> {
+    let mut serial = String::new();
+    // Note(unwrap): a serial number is always 12 characters.
+    write!(serial, "{}", SerialNumber::fetch()).unwrap();
+    serial
+}
+
+/// Client id which is unique for this device, being at most 23 characters as MQTT requires.
+pub fn client_id() -> String<23> {
+    let mut client_id = String::new();
+    write!(client_id, "{}-{}", ROOT, SerialNumber::fetch()).unwrap();
+    client_id
+}
+
+/// Namespace of this device, `slakkotron/<serial>`.
+pub fn device_prefix() -> String<TOPIC_SIZE> {
+    let mut prefix = String::new();
+    write!(prefix, "{}/{}", ROOT, SerialNumber::fetch()).unwrap();
+    prefix
+}
+
+/// Namespace of a group of devices, `slakkotron/group/<group>`.
+fn group_prefix(group: &str) -> String<TOPIC_SIZE> {
+    let mut prefix = String::new();
+    // Note(unwrap): group names are small enough to always fit.
+    write!(prefix, "{}/{}/{}", ROOT, GROUP_LEVEL, group).unwrap();
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> Identity {
+        Identity {
+            name: None,
+            groups: Vec::from_slice(&["lab".try_into().unwrap()]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn strips_device_prefix() {
+        let topic = format!("{}/cmd/config", device_prefix());
+        assert_eq!(identity().strip(&topic), Some(("cmd/config", false)));
+        assert_eq!(device_prefix().as_str(), "slakkotron/123456789abc");
+    }
+
+    #[test]
+    fn strips_group_prefix_of_member() {
+        let identity = identity();
+        assert_eq!(
+            identity.strip("slakkotron/group/lab/cmd/preset/recall"),
+            Some(("cmd/preset/recall", true))
+        );
+        assert_eq!(identity.strip("slakkotron/group/bench/cmd/config"), None);
+        assert_eq!(
+            Identity::default().strip("slakkotron/group/lab/cmd/config"),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_other_namespaces() {
+        let identity = identity();
+        assert_eq!(identity.strip("slakkotron/cba987654321/cmd/config"), None);
+        assert_eq!(identity.strip("slakkotronx/123456789abc/cmd/config"), None);
+        assert_eq!(identity.strip("other/123456789abc/cmd/config"), None);
+        // Only topics below the namespace are addressed to us.
+        assert_eq!(identity.strip("slakkotron/123456789abc"), None);
+        assert_eq!(identity.strip("slakkotron/group/lab"), None);
+    }
+
+    #[test]
+    fn validates_group_names() {
+        assert!(identity().is_valid());
+        for group in ["", "a/b", "+", "lab#"] {
+            let identity = Identity {
+                name: None,
+                groups: Vec::from_slice(&[group.try_into().unwrap()]).unwrap(),
+            };
+            assert!(!identity.is_valid(), "{:?} is valid", group);
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! Multicast DNS responder, such that this device can be found as `slakkotron-<serial>.local`, along with the
+//! services it offers through DNS-SD.
+//!
+//! Does not probe for conflicts, as the name is unique by the serial number.
+
+use core::fmt::Write as _;
+
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_time::{Duration, Timer};
+use esp_wifi::wifi::{WifiDevice, WifiStaDevice};
+use heapless::String;
+
+use crate::bsp::HARDWARE_REVISION;
+
+use super::{
+    discovery::FIRMWARE_VERSION,
+    http,
+    identity::{self, ROOT},
+    scpi, Net,
+};
+
+mod packet;
+
+use packet::{
+    parse_query, Record, Records, Writer, CLASS_FLUSH, CLASS_IN, FLAGS_REPLY, LOCAL, SERVICES,
+    TYPE_A, TYPE_PTR, TYPE_SRV, TYPE_TXT,
+};
+pub use packet::{Service, Services};
+
+const PORT: u16 = 5353;
+const GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+
+/// Largest packet received or sent, which is well within a single frame.
+const PACKET_SIZE: usize = 1024;
+
+/// Time to live of records concerning the host, and of all others, as recommended by RFC 6762.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+/// Time to live of records in replies to resolvers that are not fully compliant, which do not expect them to be cached.
+const LEGACY_TTL: u32 = 10;
+
+/// Interval at which our address is checked for changes, which are then announced.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+
+type Instance = String<23>;
+
+impl Service {
+    fn port(&self) -> u16 {
+        match self {
+            Service::Http => http::PORT,
+            Service::Scpi => scpi::PORT,
+        }
+    }
+}
+
+/// Name of both this host and the instances of its services.
+fn instance() -> Instance {
+    let mut instance = Instance::new();
+    // Note(unwrap): a serial number is always 12 characters.
+    write!(instance, "{}-{}", ROOT, identity::serial()).unwrap();
+    instance
+}
+
+struct Reply<'a> {
+    instance: &'a str,
+    address: Ipv4Address,
+    /// Whether replying to a resolver that is not fully compliant, which expects a plain DNS reply.
+    legacy: bool,
+}
+
+impl Reply<'_> {
+    fn write_record(&self, writer: &mut Writer, record: Record) -> Option<()> {
+        let instance = self.instance;
+        let (host_ttl, other_ttl, flush) = if self.legacy {
+            (LEGACY_TTL, LEGACY_TTL, 0)
+        } else {
+            (HOST_TTL, OTHER_TTL, CLASS_FLUSH)
+        };
+
+        match record {
+            Record::Address => writer.record(
+                &[instance, LOCAL],
+                TYPE_A,
+                CLASS_IN | flush,
+                host_ttl,
+                |writer| writer.bytes(self.address.as_bytes()),
+            ),
+            Record::Kind(service) => writer.record(
+                &[SERVICES, LOCAL],
+                TYPE_PTR,
+                CLASS_IN,
+                other_ttl,
+                |writer| writer.name(&[service.kind(), LOCAL]),
+            ),
+            Record::Instance(service) => writer.record(
+                &[service.kind(), LOCAL],
+                TYPE_PTR,
+                CLASS_IN,
+                other_ttl,
+                |writer| writer.name(&[instance, service.kind(), LOCAL]),
+            ),
+            Record::Srv(service) => writer.record(
+                &[instance, service.kind(), LOCAL],
+                TYPE_SRV,
+                CLASS_IN | flush,
+                host_ttl,
+                |writer| {
+                    // Priority and weight, which are irrelevant with a single instance.
+                    writer.u16(0)?;
+                    writer.u16(0)?;
+                    writer.u16(service.port())?;
+                    writer.name(&[instance, LOCAL])
+                },
+            ),
+            Record::Txt(service) => writer.record(
+                &[instance, service.kind(), LOCAL],
+                TYPE_TXT,
+                CLASS_IN | flush,
+                other_ttl,
+                |writer| {
+                    for (key, value) in [
+                        ("serial", identity::serial().as_str()),
+                        ("fw", FIRMWARE_VERSION),
+                        ("hw", HARDWARE_REVISION),
+                    ] {
+                        writer.bytes(&[(key.len() + 1 + value.len()) as u8])?;
+                        writer.bytes(key.as_bytes())?;
+                        writer.bytes(b"=")?;
+                        writer.bytes(value.as_bytes())?;
+                    }
+                    Some(())
+                },
+            ),
+        }
+    }
+
+    /// Write the records as a reply to `query`, yielding its length.
+    ///
+    /// Legacy replies repeat the id and questions of the query, as a plain DNS reply would.
+    fn write(
+        &self,
+        buffer: &mut [u8],
+        records: &Records,
+        query: Option<(u16, u16, &[u8])>,
+    ) -> usize {
+        let mut writer = Writer { buffer, len: 0 };
+        let (id, questions, question_bytes) = query.unwrap_or((0, 0, &[]));
+
+        // Note(unwrap): the buffer is large enough for the header and the questions we received.
+        writer.u16(id).unwrap();
+        writer.u16(FLAGS_REPLY).unwrap();
+        writer.u16(questions).unwrap();
+        writer.bytes(&[0; 6]).unwrap();
+        writer.bytes(question_bytes).unwrap();
+
+        // Records that do not fit are left out, rolling back those partially written.
+        let mut write_section = |records: &[Record]| {
+            let mut count = 0;
+            for record in records {
+                let len = writer.len;
+                if self.write_record(&mut writer, *record).is_none() {
+                    writer.len = len;
+                    break;
+                }
+                count += 1;
+            }
+            count
+        };
+        let answers = write_section(&records.answers);
+        let additionals = write_section(&records.additionals);
+
+        writer.set_u16(6, answers);
+        writer.set_u16(10, additionals);
+        writer.len
+    }
+}
+
+#[embassy_executor::task]
+pub async fn mdns_task(
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    system: &'static Net,
+) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; PACKET_SIZE];
+    let mut packet = [0; PACKET_SIZE];
+    let mut reply = [0; PACKET_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // Note(unwrap): the port is not used by any other socket.
+    socket.bind(PORT).unwrap();
+
+    while stack.config_v4().is_none() {
+        Timer::after(Duration::from_millis(50)).await;
+    }
+    if let Err(e) = stack.join_multicast_group(GROUP).await {
+        log::error!("Failed to join mDNS group: {:?}", e);
+        return;
+    }
+
+    let instance = instance();
+    let group = IpEndpoint::new(GROUP.into(), PORT);
+    // Address last announced, which is announced again whenever it changes.
+    let mut announced = None;
+
+    loop {
+        let address = stack.config_v4().map(|config| config.address.address());
+        if address.is_none() {
+            announced = None;
+        }
+
+        if let Some(address) = address.filter(|address| announced != Some(*address)) {
+            log::info!("Announcing {}.local at {}", instance, address);
+            let records = Records::all(&system.services);
+            let len = Reply {
+                instance: &instance,
+                address,
+                legacy: false,
+            }
+            .write(&mut reply, &records, None);
+
+            // Announced twice, in case the first one is lost.
+            for _ in 0..2 {
+                if let Err(e) = socket.send_to(&reply[..len], group).await {
+                    log::warn!("Failed to announce over mDNS: {:?}", e);
+                }
+                Timer::after(Duration::from_secs(1)).await;
+            }
+            announced = Some(address);
+        }
+
+        let (len, source) = match select(
+            socket.recv_from(&mut packet),
+            Timer::after(ANNOUNCE_INTERVAL),
+        )
+        .await
+        {
+            Either::First(Ok(received)) => received,
+            Either::First(Err(e)) => {
+                log::warn!("Failed to receive mDNS query: {:?}", e);
+                continue;
+            }
+            Either::Second(()) => continue,
+        };
+
+        let Some(address) = announced else {
+            continue;
+        };
+        let Some((records, id, questions, question_bytes)) =
+            parse_query(&packet[..len], &instance, &system.services)
+        else {
+            continue;
+        };
+        if records.is_empty() {
+            continue;
+        }
+
+        // Resolvers that are not fully compliant query from another port, and expect a plain DNS reply.
+        let legacy = source.port != PORT;
+        let reply_to = if legacy { source } else { group };
+        let len = Reply {
+            instance: &instance,
+            address,
+            legacy,
+        }
+        .write(
+            &mut reply,
+            &records,
+            legacy.then_some((id, questions, question_bytes)),
+        );
+
+        if let Err(e) = socket.send_to(&reply[..len], reply_to).await {
+            log::warn!("Failed to reply over mDNS: {:?}", e);
+        }
+    }
+}
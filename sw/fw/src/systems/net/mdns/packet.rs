@@ -0,0 +1,578 @@
+//! Wire format of mDNS, independent of the socket it is exchanged over.
+
+use heapless::String;
+use portable_atomic::{AtomicU8, Ordering};
+
+/// Maximum number of compression pointers followed in a single name, preventing loops.
+const MAX_POINTERS: usize = 16;
+const MAX_RECORDS: usize = 16;
+
+pub(super) const TYPE_A: u16 = 1;
+pub(super) const TYPE_PTR: u16 = 12;
+pub(super) const TYPE_TXT: u16 = 16;
+pub(super) const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+pub(super) const CLASS_IN: u16 = 1;
+/// Set on records only we own, or on questions to request a unicast reply.
+pub(super) const CLASS_FLUSH: u16 = 0x8000;
+
+/// Flags of a reply, being an authoritative answer.
+pub(super) const FLAGS_REPLY: u16 = 0x8400;
+const FLAG_QR: u16 = 0x8000;
+const OPCODE_MASK: u16 = 0x7800;
+
+pub(super) const LOCAL: &str = "local";
+/// Name under which all service types are enumerated.
+pub(super) const SERVICES: &str = "_services._dns-sd._udp";
+
+type Name = String<255>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Service {
+    Http,
+    Scpi,
+}
+
+impl Service {
+    const ALL: [Service; 2] = [Service::Http, Service::Scpi];
+
+    /// Service type, as registered with IANA.
+    pub(super) fn kind(&self) -> &'static str {
+        match self {
+            Service::Http => "_http._tcp",
+            Service::Scpi => "_scpi-raw._tcp",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+/// Services to advertise, being those that were started.
+pub struct Services(AtomicU8);
+
+impl Services {
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(0))
+    }
+
+    pub fn advertise(&self, service: Service) {
+        self.0.fetch_or(service.bit(), Ordering::Relaxed);
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = Service> {
+        let bits = self.0.load(Ordering::Relaxed);
+        Service::ALL
+            .into_iter()
+            .filter(move |service| bits & service.bit() != 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Record {
+    /// Address of this host.
+    Address,
+    /// Type of a service, when enumerating all types.
+    Kind(Service),
+    /// Instance of a service, when browsing for its type.
+    Instance(Service),
+    /// Host and port of an instance.
+    Srv(Service),
+    Txt(Service),
+}
+
+/// Records to reply, where those in the additional section save the querier another round trip.
+#[derive(Default)]
+pub(super) struct Records {
+    pub(super) answers: heapless::Vec<Record, MAX_RECORDS>,
+    pub(super) additionals: heapless::Vec<Record, MAX_RECORDS>,
+}
+
+impl Records {
+    fn answer(&mut self, record: Record) {
+        if !self.answers.contains(&record) {
+            // Ignored when full, as there are not that many records to give.
+            let _ = self.answers.push(record);
+        }
+    }
+
+    fn add(&mut self, record: Record) {
+        if !self.additionals.contains(&record) {
+            let _ = self.additionals.push(record);
+        }
+    }
+
+    /// Collect the records answering a question.
+    fn question(&mut self, name: &str, kind: u16, instance: &str, services: &Services) {
+        let wants = |wanted| kind == wanted || kind == TYPE_ANY;
+
+        if is_name(name, &[instance, LOCAL]) && wants(TYPE_A) {
+            self.answer(Record::Address);
+        }
+        for service in services.iter() {
+            if is_name(name, &[SERVICES, LOCAL]) && wants(TYPE_PTR) {
+                self.answer(Record::Kind(service));
+            }
+            if is_name(name, &[service.kind(), LOCAL]) && wants(TYPE_PTR) {
+                self.answer(Record::Instance(service));
+                self.add(Record::Srv(service));
+                self.add(Record::Txt(service));
+                self.add(Record::Address);
+            }
+            if is_name(name, &[instance, service.kind(), LOCAL]) {
+                if wants(TYPE_SRV) {
+                    self.answer(Record::Srv(service));
+                    self.add(Record::Address);
+                }
+                if wants(TYPE_TXT) {
+                    self.answer(Record::Txt(service));
+                }
+            }
+        }
+    }
+
+    /// All records, to announce them unsolicited.
+    pub(super) fn all(services: &Services) -> Self {
+        let mut records = Self::default();
+        records.answer(Record::Address);
+        for service in services.iter() {
+            records.answer(Record::Kind(service));
+            records.answer(Record::Instance(service));
+            records.answer(Record::Srv(service));
+            records.answer(Record::Txt(service));
+        }
+        records
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.answers.is_empty()
+    }
+}
+
+/// Whether `name` consists of `labels`, some of which may themselves contain multiple labels.
+fn is_name(name: &str, labels: &[&str]) -> bool {
+    let mut rest = name.as_bytes();
+    for (index, label) in labels.iter().enumerate() {
+        if index > 0 {
+            match rest.split_first() {
+                Some((b'.', tail)) => rest = tail,
+                _ => return false,
+            }
+        }
+        match rest.get(..label.len()) {
+            Some(head) if head.eq_ignore_ascii_case(label.as_bytes()) => {
+                rest = &rest[label.len()..]
+            }
+            _ => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Read a name in dotted form, yielding the offset following it.
+///
+/// The name is none when it cannot be one of ours, as it is not valid UTF-8 or too long.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(Option<Name>, usize)> {
+    let mut name = Some(Name::new());
+    // Offset following the name where it started, before following any pointers.
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => return Some((name, end.unwrap_or(offset + 1))),
+            len if len & 0xC0 == 0xC0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                offset = (len & 0x3F) << 8 | *packet.get(offset + 1)? as usize;
+            }
+            len if len < 64 => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                name = name.and_then(|mut name| {
+                    if !name.is_empty() {
+                        name.push('.').ok()?;
+                    }
+                    name.push_str(core::str::from_utf8(label).ok()?).ok()?;
+                    Some(name)
+                });
+                offset += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Writer of a reply, failing when it does not fit.
+pub(super) struct Writer<'a> {
+    pub(super) buffer: &'a mut [u8],
+    pub(super) len: usize,
+}
+
+impl Writer<'_> {
+    pub(super) fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    pub(super) fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub(super) fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub(super) fn set_u16(&mut self, offset: usize, value: u16) {
+        self.buffer[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Write a name, without compression.
+    pub(super) fn name(&mut self, labels: &[&str]) -> Option<()> {
+        for label in labels.iter().flat_map(|labels| labels.split('.')) {
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    /// Write the header of a record and its data, prefixed by its length.
+    pub(super) fn record(
+        &mut self,
+        name: &[&str],
+        kind: u16,
+        class: u16,
+        ttl: u32,
+        data: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(kind)?;
+        self.u16(class)?;
+        self.u32(ttl)?;
+
+        let start = self.len;
+        self.u16(0)?;
+        data(self)?;
+        let len = self.len - start - 2;
+        self.set_u16(start, len as u16);
+        Some(())
+    }
+}
+
+/// Parse a query, yielding the records to reply, along with its id and the bytes of its questions.
+pub(super) fn parse_query<'a>(
+    packet: &'a [u8],
+    instance: &str,
+    services: &Services,
+) -> Option<(Records, u16, u16, &'a [u8])> {
+    let id = read_u16(packet, 0)?;
+    let flags = read_u16(packet, 2)?;
+    if flags & (FLAG_QR | OPCODE_MASK) != 0 {
+        // Replies of others, or queries of another kind.
+        return None;
+    }
+    let questions = read_u16(packet, 4)?;
+
+    let mut records = Records::default();
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (name, next) = read_name(packet, offset)?;
+        let kind = read_u16(packet, next)?;
+        offset = next + 4;
+
+        if let Some(name) = name {
+            records.question(&name, kind, instance, services);
+        }
+    }
+
+    // Question names may point into the header, which is at the same place in our reply.
+    Some((records, id, questions, packet.get(12..offset)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+
+    const INSTANCE: &str = "slakkotron-0123456789ab";
+
+    type Packet = Vec<u8, 1024>;
+
+    /// Query with a header, followed by `questions` encoded as is.
+    fn query(id: u16, flags: u16, count: u16, questions: &[u8]) -> Packet {
+        let mut packet = Packet::new();
+        for field in [id, flags, count, 0, 0, 0] {
+            packet.extend_from_slice(&field.to_be_bytes()).unwrap();
+        }
+        packet.extend_from_slice(questions).unwrap();
+        packet
+    }
+
+    /// Name as encoded in a packet, without compression.
+    fn name(dotted: &str) -> Packet {
+        let mut packet = Packet::new();
+        let mut writer = Writer {
+            buffer: &mut [0; 1024],
+            len: 0,
+        };
+        writer.name(&[dotted]).unwrap();
+        packet
+            .extend_from_slice(&writer.buffer[..writer.len])
+            .unwrap();
+        packet
+    }
+
+    fn question(dotted: &str, kind: u16) -> Packet {
+        let mut packet = name(dotted);
+        packet.extend_from_slice(&kind.to_be_bytes()).unwrap();
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes()).unwrap();
+        packet
+    }
+
+    fn services(advertised: &[Service]) -> Services {
+        let services = Services::new();
+        for service in advertised {
+            services.advertise(*service);
+        }
+        services
+    }
+
+    fn records(name: &str, kind: u16, services: &Services) -> Records {
+        let mut records = Records::default();
+        records.question(name, kind, INSTANCE, services);
+        records
+    }
+
+    #[test]
+    fn reads_names() {
+        let packet = name("slakkotron-0123456789ab.local");
+        assert_eq!(
+            read_name(&packet, 0),
+            Some((
+                Some("slakkotron-0123456789ab.local".try_into().unwrap()),
+                packet.len()
+            ))
+        );
+
+        // The root, being an empty name.
+        assert_eq!(read_name(&[0], 0), Some((Some(Name::new()), 1)));
+    }
+
+    #[test]
+    fn reads_compressed_names() {
+        // `_http._tcp.local`, followed by `slakkotron-0123456789ab` and a pointer to the former.
+        let mut packet = name("_http._tcp.local");
+        let instance = packet.len();
+        packet.push(INSTANCE.len() as u8).unwrap();
+        packet.extend_from_slice(INSTANCE.as_bytes()).unwrap();
+        packet.extend_from_slice(&[0xC0, 0]).unwrap();
+        // Followed by a name that is only a pointer.
+        let pointer = packet.len();
+        packet.extend_from_slice(&[0xC0, instance as u8]).unwrap();
+
+        let expected: Name = "slakkotron-0123456789ab._http._tcp.local"
+            .try_into()
+            .unwrap();
+        // The name ends after the first pointer, rather than where the pointer leads.
+        assert_eq!(
+            read_name(&packet, instance),
+            Some((Some(expected.clone()), pointer))
+        );
+        assert_eq!(
+            read_name(&packet, pointer),
+            Some((Some(expected), pointer + 2))
+        );
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        // Pointing to itself.
+        assert_eq!(read_name(&[0xC0, 0], 0), None);
+        // Pointing to each other.
+        assert_eq!(read_name(&[0xC0, 2, 0xC0, 0], 0), None);
+        // A label followed by a pointer to itself, which would grow the name forever.
+        assert_eq!(read_name(&[1, b'a', 0xC0, 0], 0), None);
+
+        // Chains of pointers are followed, up to a limit.
+        let mut packet = Packet::new();
+        packet.push(0).unwrap();
+        for pointer in 0..MAX_POINTERS + 1 {
+            // Pointing to the previous pointer, or the root for the first one.
+            let target = (2 * pointer).saturating_sub(1);
+            packet.extend_from_slice(&[0xC0, target as u8]).unwrap();
+        }
+        // Offset of the pointer from which a number of pointers are followed.
+        let start = |pointers: usize| 1 + 2 * (pointers - 1);
+        assert_eq!(
+            read_name(&packet, start(MAX_POINTERS)),
+            Some((Some(Name::new()), start(MAX_POINTERS) + 2))
+        );
+        assert_eq!(read_name(&packet, start(MAX_POINTERS + 1)), None);
+    }
+
+    #[test]
+    fn rejects_truncated_names() {
+        let packet = name("slakkotron.local");
+        for len in 0..packet.len() {
+            assert_eq!(read_name(&packet[..len], 0), None, "Truncated to {}", len);
+        }
+
+        // A pointer without its offset, or pointing beyond the packet.
+        assert_eq!(read_name(&[0xC0], 0), None);
+        assert_eq!(read_name(&[0xC0, 2], 0), None);
+        // Label types other than pointers are reserved.
+        assert_eq!(read_name(&[0x40, 0], 0), None);
+        assert_eq!(read_name(&[0x80, 0], 0), None);
+    }
+
+    #[test]
+    fn skips_names_that_are_not_ours() {
+        // Not UTF-8, yet the name is still skipped such that the remainder of the packet can be read.
+        assert_eq!(read_name(&[2, 0xFF, 0xFE, 0], 0), Some((None, 4)));
+
+        // Longer than a name can be.
+        let mut packet = Packet::new();
+        for _ in 0..5 {
+            packet.push(63).unwrap();
+            packet.extend_from_slice(&[b'a'; 63]).unwrap();
+        }
+        packet.push(0).unwrap();
+        assert_eq!(read_name(&packet, 0), Some((None, packet.len())));
+    }
+
+    #[test]
+    fn answers_address() {
+        let services = services(&[]);
+        let instance = [INSTANCE, ".local"].concat();
+
+        for kind in [TYPE_A, TYPE_ANY] {
+            let records = records(&instance, kind, &services);
+            assert_eq!(records.answers, [Record::Address]);
+            assert!(records.additionals.is_empty());
+        }
+
+        // Names are case-insensitive.
+        let upper = instance.to_ascii_uppercase();
+        assert_eq!(
+            records(&upper, TYPE_A, &services).answers,
+            [Record::Address]
+        );
+
+        assert!(records(&instance, TYPE_TXT, &services).is_empty());
+        assert!(records("other.local", TYPE_A, &services).is_empty());
+        assert!(records(INSTANCE, TYPE_A, &services).is_empty());
+        assert!(records(&[&instance, ".local"].concat(), TYPE_A, &services).is_empty());
+    }
+
+    #[test]
+    fn answers_advertised_services() {
+        let services = services(&[Service::Http]);
+
+        let records_of = |name: &str, kind| records(name, kind, &services);
+        let enumerated = records_of("_services._dns-sd._udp.local", TYPE_PTR);
+        assert_eq!(enumerated.answers, [Record::Kind(Service::Http)]);
+
+        let browsed = records_of("_http._tcp.local", TYPE_PTR);
+        assert_eq!(browsed.answers, [Record::Instance(Service::Http)]);
+        assert_eq!(
+            browsed.additionals,
+            [
+                Record::Srv(Service::Http),
+                Record::Txt(Service::Http),
+                Record::Address
+            ]
+        );
+
+        let instance = [INSTANCE, "._http._tcp.local"].concat();
+        let resolved = records_of(&instance, TYPE_SRV);
+        assert_eq!(resolved.answers, [Record::Srv(Service::Http)]);
+        assert_eq!(resolved.additionals, [Record::Address]);
+        assert_eq!(
+            records_of(&instance, TYPE_TXT).answers,
+            [Record::Txt(Service::Http)]
+        );
+        assert_eq!(
+            records_of(&instance, TYPE_ANY).answers,
+            [Record::Srv(Service::Http), Record::Txt(Service::Http)]
+        );
+
+        // Only services that were started are advertised.
+        assert!(records_of("_scpi-raw._tcp.local", TYPE_PTR).is_empty());
+        assert!(records_of(&[INSTANCE, "._scpi-raw._tcp.local"].concat(), TYPE_SRV).is_empty());
+        assert!(records("_services._dns-sd._udp.local", TYPE_PTR, &Services::new()).is_empty());
+    }
+
+    #[test]
+    fn parses_queries() {
+        let services = services(&[Service::Http, Service::Scpi]);
+
+        // Browsing for a service, and resolving its instance, compressed by pointing to the former.
+        let mut questions = question("_scpi-raw._tcp.local", TYPE_PTR);
+        let pointer = 12 + questions.len();
+        questions.push(INSTANCE.len() as u8).unwrap();
+        questions.extend_from_slice(INSTANCE.as_bytes()).unwrap();
+        questions.extend_from_slice(&[0xC0, 12]).unwrap();
+        questions
+            .extend_from_slice(&TYPE_SRV.to_be_bytes())
+            .unwrap();
+        questions
+            .extend_from_slice(&(CLASS_IN | CLASS_FLUSH).to_be_bytes())
+            .unwrap();
+        // Followed by an answer the querier already knows of, which is ignored.
+        let mut packet = query(0x1234, 0, 2, &questions);
+        packet.extend_from_slice(&[0xC0, pointer as u8]).unwrap();
+
+        let (records, id, count, question_bytes) =
+            parse_query(&packet, INSTANCE, &services).unwrap();
+        assert_eq!((id, count), (0x1234, 2));
+        assert_eq!(question_bytes, &questions[..]);
+        assert_eq!(
+            records.answers,
+            [Record::Instance(Service::Scpi), Record::Srv(Service::Scpi)]
+        );
+        assert_eq!(
+            records.additionals,
+            [
+                Record::Srv(Service::Scpi),
+                Record::Txt(Service::Scpi),
+                Record::Address
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_other_packets() {
+        let services = services(&[Service::Http]);
+        let questions = question("_http._tcp.local", TYPE_PTR);
+
+        // Replies, and other opcodes than a standard query.
+        assert!(parse_query(&query(0, FLAGS_REPLY, 1, &questions), INSTANCE, &services).is_none());
+        assert!(parse_query(&query(0, 0x2800, 1, &questions), INSTANCE, &services).is_none());
+
+        // Truncated anywhere, or claiming more questions than it has.
+        let packet = query(0, 0, 1, &questions);
+        for len in 0..packet.len() {
+            assert!(
+                parse_query(&packet[..len], INSTANCE, &services).is_none(),
+                "Truncated to {}",
+                len
+            );
+        }
+        assert!(parse_query(&packet, INSTANCE, &services).is_some());
+        assert!(parse_query(&query(0, 0, 2, &questions), INSTANCE, &services).is_none());
+    }
+}
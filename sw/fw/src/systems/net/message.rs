@@ -0,0 +1,113 @@
+//! Messages to publish, and the topics of the device they are published to.
+
+use embassy_time::Instant;
+use heapless::{String, Vec};
+use serde::Serialize;
+
+use super::{identity, outbox::Priority};
+
+pub const TOPIC_SIZE: usize = 64;
+pub const CONTENT_SIZE: usize = 640;
+
+pub struct Message {
+    pub(super) topic: String<TOPIC_SIZE>,
+    pub(super) content: Vec<u8, CONTENT_SIZE>,
+    /// Whether the broker should keep the message for clients subscribing later on.
+    pub(super) retain: bool,
+    pub(super) priority: Priority,
+    pub(super) created: Instant,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    TopicTooLarge,
+    ContentTooLarge,
+}
+
+#[derive(Debug)]
+pub enum Topic {
+    Stats,
+    Record,
+    Config,
+    StorageHealth,
+    Presets,
+    Journal,
+    Identity,
+    Status,
+    Availability,
+    Error,
+    Warning,
+}
+
+impl Topic {
+    /// Part of the topic below the namespace of the device.
+    fn suffix(&self) -> &'static str {
+        match self {
+            Topic::Stats => "stats",
+            Topic::Record => "record",
+            Topic::Config => "config",
+            Topic::StorageHealth => "storage",
+            Topic::Presets => "presets",
+            Topic::Journal => "journal",
+            Topic::Identity => "identity",
+            Topic::Status => "status",
+            Topic::Availability => "availability",
+            Topic::Error => "error",
+            Topic::Warning => "warning",
+        }
+    }
+
+    fn priority(&self) -> Priority {
+        match self {
+            Topic::Stats => Priority::Telemetry,
+            Topic::Record => Priority::Record,
+            Topic::Journal | Topic::Error | Topic::Warning => Priority::Alert,
+            _ => Priority::State,
+        }
+    }
+
+    pub fn to_str(&self) -> Result<String<TOPIC_SIZE>, ()> {
+        let mut topic = identity::device_prefix();
+        topic.push('/')?;
+        topic.push_str(self.suffix())?;
+        Ok(topic)
+    }
+}
+
+impl Message {
+    pub fn new(topic: &Topic, value: &impl Serialize) -> Result<Self, Error> {
+        let priority = topic.priority();
+        let topic = topic.to_str().map_err(|_| Error::TopicTooLarge)?;
+        Ok(Self {
+            priority,
+            ..Self::with_topic(topic, value)?
+        })
+    }
+
+    /// Message to a topic outside of the namespace of the device.
+    pub(super) fn with_topic(
+        topic: String<TOPIC_SIZE>,
+        value: &impl Serialize,
+    ) -> Result<Self, Error> {
+        let mut content: Vec<u8, CONTENT_SIZE> = Vec::new();
+        content.resize_default(CONTENT_SIZE).unwrap();
+        let size =
+            serde_json_core::to_slice(value, &mut content).map_err(|_| Error::ContentTooLarge)?;
+        content.truncate(size);
+
+        Ok(Self {
+            topic,
+            content,
+            retain: false,
+            priority: Priority::State,
+            created: Instant::now(),
+        })
+    }
+
+    pub fn retained(self) -> Self {
+        Self {
+            retain: true,
+            ..self
+        }
+    }
+}
@@ -0,0 +1,765 @@
+//! Networking and MQTT client.
+
+use embassy_executor::Spawner;
+use embassy_net::{tcp::TcpSocket, IpEndpoint, Stack, StackResources};
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    channel::Channel,
+    mutex::Mutex,
+    pubsub::PubSubBehavior,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use embedded_tls::{TlsConnection, TlsContext};
+use esp_wifi::wifi::{
+    ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiStaDevice,
+    WifiState,
+};
+use heapless::String;
+use rust_mqtt::{
+    client::{client::MqttClient, client_config::ClientConfig},
+    packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
+    utils::rng_generator::CountingRng,
+};
+use serde::{Deserialize, Serialize};
+use static_cell::{ConstStaticCell, StaticCell};
+
+use crate::{
+    bsp::Wifi,
+    systems::{
+        config::Config,
+        presets::Presets,
+        storage::{self, MaxSize, Storage, StorageEntry, StorageKey},
+        usb_pd::Usbpd,
+        watchdog::{Watchdog, WatchdogTicket, WATCHDOG_DEADLINE},
+    },
+    util::{PubSub, Sub},
+};
+
+mod broker;
+mod commands;
+mod connection;
+mod discovery;
+mod http;
+mod identity;
+mod mdns;
+mod message;
+mod outbox;
+mod panel;
+mod router;
+mod scpi;
+mod tls;
+
+pub use broker::BrokerSettings;
+pub use connection::{Disconnect, Failure, Report as ConnectionReport};
+pub use http::Http;
+pub use identity::{Identity, Report as IdentityReport};
+pub use message::{Error, Message, Topic};
+pub use scpi::Scpi;
+
+use commands::{Command, MAX_ROUTES};
+use connection::{Backoff, Metrics};
+use mdns::Services;
+use message::{CONTENT_SIZE, TOPIC_SIZE};
+use outbox::Outbox;
+use router::Router;
+use tls::{HardwareRng, RecordBuffers};
+
+/// Credentials to use until others have been provisioned.
+const DEFAULT_SSID: Option<&str> = option_env!("WIFI_SSID");
+const DEFAULT_PASSWORD: Option<&str> = option_env!("WIFI_PASSWORD");
+
+/// Secret required for destructive commands, which are disabled when not set.
+const COMMAND_TOKEN: Option<&str> = option_env!("PSU_COMMAND_TOKEN");
+
+type MessageChannel<T> = Channel<NoopRawMutex, T, 1>;
+
+/// Large enough to receive a certificate, see [tls::MAX_DER_SIZE].
+const MAX_PACKET_SIZE: usize = 4096;
+const SOCKET_BUFFER_SIZE: usize = 1024;
+const MAX_PROPERTIES: usize = 20;
+
+/// Sockets for DHCP, DNS, mDNS, MQTT and SCPI, and those of the HTTP server.
+const MAX_SOCKETS: usize = 5 + http::MAX_CONNECTIONS;
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct WifiCredentials {
+    pub ssid: String<32>,
+    pub password: String<64>,
+}
+
+impl MaxSize for WifiCredentials {
+    const MAX_SIZE: usize = String::<32>::MAX_SIZE + String::<64>::MAX_SIZE;
+}
+
+impl StorageEntry for WifiCredentials {
+    const KEY: StorageKey = StorageKey::WifiCredentials;
+}
+
+impl WifiCredentials {
+    fn default_from_env() -> Option<Self> {
+        Some(Self {
+            ssid: DEFAULT_SSID?.try_into().ok()?,
+            password: DEFAULT_PASSWORD?.try_into().ok()?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Event {
+    ConnectedWifi,
+    DisconnectedWifi,
+    /// Connecting to the broker at this index of the [BrokerSettings].
+    ConnectingMQTT {
+        broker: usize,
+    },
+    ConnectedMQTT,
+    FailedMQTT {
+        broker: usize,
+        failure: Failure,
+    },
+    /// Waiting before connecting again.
+    BackingOff {
+        delay: Duration,
+    },
+    DisconnectedMQTT {
+        reason: Disconnect,
+        session: Duration,
+    },
+}
+
+pub struct Net {
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    outbox: Outbox,
+    journal_channel: MessageChannel<()>,
+    wifi_credentials: Mutex<CriticalSectionRawMutex, Option<WifiCredentials>>,
+    wifi_channel: MessageChannel<()>,
+    broker_settings: Mutex<CriticalSectionRawMutex, BrokerSettings>,
+    /// Notified to reconnect to the broker, applying new settings or subscriptions.
+    broker_channel: MessageChannel<()>,
+    identity: Mutex<CriticalSectionRawMutex, Identity>,
+    tls_credentials: Mutex<CriticalSectionRawMutex, tls::Credentials>,
+    metrics: Mutex<CriticalSectionRawMutex, Metrics>,
+    router: Router<Command, MAX_ROUTES>,
+    event_channel: PubSub<Event>,
+    /// Services advertised over mDNS.
+    services: Services,
+    config: &'static Config,
+    storage: &'static Storage,
+    usbpd: &'static Usbpd,
+    presets: &'static Presets,
+}
+
+impl Net {
+    pub async fn init(
+        wifi: Wifi,
+        config: &'static Config,
+        storage: &'static Storage,
+        usbpd: &'static Usbpd,
+        presets: &'static Presets,
+        watchdog: &'static Watchdog,
+        spawner: &Spawner,
+    ) -> &'static Net {
+        let wifi_credentials = match storage.fetch::<WifiCredentials>().await {
+            Ok(Some(credentials)) => Some(credentials),
+            Ok(None) => WifiCredentials::default_from_env(),
+            Err(e) => {
+                log::error!("Failed to fetch WiFi credentials: {:?}", e);
+                WifiCredentials::default_from_env()
+            }
+        };
+
+        let broker_settings = match storage.fetch::<BrokerSettings>().await {
+            Ok(settings) => settings.unwrap_or_default(),
+            Err(e) => {
+                log::error!("Failed to fetch broker settings, using defaults: {:?}", e);
+                BrokerSettings::default()
+            }
+        };
+
+        let identity = match storage.fetch::<Identity>().await {
+            Ok(identity) => identity.unwrap_or_default(),
+            Err(e) => {
+                log::error!("Failed to fetch identity: {:?}", e);
+                Identity::default()
+            }
+        };
+
+        let tls_credentials = tls::Credentials::fetch(storage).await;
+
+        let netconfig = embassy_net::Config::dhcpv4(Default::default());
+
+        static RESOURCES: StaticCell<StackResources<MAX_SOCKETS>> = StaticCell::new();
+        let resources = RESOURCES.init(StackResources::<MAX_SOCKETS>::new());
+
+        static STACK: StaticCell<Stack<WifiDevice<'_, WifiStaDevice>>> = StaticCell::new();
+        let stack = STACK.init(Stack::new(wifi.device, netconfig, resources, wifi.seed));
+
+        static SYSTEM: StaticCell<Net> = StaticCell::new();
+        let system: &mut Net = SYSTEM.init(Net {
+            stack,
+            outbox: Outbox::new(),
+            journal_channel: MessageChannel::new(),
+            wifi_credentials: Mutex::new(wifi_credentials),
+            wifi_channel: MessageChannel::new(),
+            broker_settings: Mutex::new(broker_settings),
+            broker_channel: MessageChannel::new(),
+            identity: Mutex::new(identity),
+            tls_credentials: Mutex::new(tls_credentials),
+            metrics: Mutex::new(Metrics::default()),
+            router: commands::routes(),
+            event_channel: PubSub::new(),
+            services: Services::new(),
+            config,
+            storage,
+            usbpd,
+            presets,
+        });
+
+        spawner
+            .spawn(connection_task(wifi.controller, system))
+            .unwrap();
+        spawner.spawn(stack_task(stack)).unwrap();
+        spawner.spawn(journal_task(system)).unwrap();
+        spawner.spawn(mdns::mdns_task(stack, system)).unwrap();
+        static TLS_BUFFERS: ConstStaticCell<RecordBuffers> =
+            ConstStaticCell::new(RecordBuffers::new());
+        let tls_buffers = TLS_BUFFERS.take();
+
+        spawner
+            .spawn(net_task(
+                stack,
+                system,
+                wifi.seed,
+                HardwareRng(wifi.rng),
+                tls_buffers,
+                watchdog.ticket().await,
+            ))
+            .unwrap();
+
+        system
+    }
+
+    /// Queue a message, sent as soon as we are connected.
+    ///
+    /// Waits for room in the outbox only for alerts, see [outbox::Priority].
+    pub async fn send(&self, mut message: Message) {
+        outbox::stamp(&mut message);
+        self.outbox.push(message).await
+    }
+
+    pub fn event_subscriber(&'static self) -> Sub<Event> {
+        self.event_channel.subscriber().unwrap()
+    }
+
+    /// Persist new WiFi credentials, and reconnect using them.
+    pub async fn provision_wifi(&self, credentials: WifiCredentials) -> Result<(), storage::Error> {
+        self.storage.store(credentials.clone()).await?;
+        *self.wifi_credentials.lock().await = Some(credentials);
+        let _ = self.wifi_channel.try_send(());
+
+        log::info!("Provisioned WiFi credentials");
+        Ok(())
+    }
+
+    /// Network currently used, if any credentials were provisioned.
+    pub async fn wifi_ssid(&self) -> Option<String<32>> {
+        self.wifi_credentials
+            .lock()
+            .await
+            .as_ref()
+            .map(|credentials| credentials.ssid.clone())
+    }
+
+    /// Persist new broker settings, and reconnect using them.
+    pub async fn provision_broker(&self, settings: BrokerSettings) -> Result<(), storage::Error> {
+        self.storage.store(settings.clone()).await?;
+        *self.broker_settings.lock().await = settings;
+        let _ = self.broker_channel.try_send(());
+
+        log::info!("Provisioned broker settings");
+        Ok(())
+    }
+
+    /// Persist a new identity, reconnecting to resubscribe and announce it when it changed.
+    pub async fn assign_identity(&self, identity: Identity) -> Result<(), storage::Error> {
+        self.storage.store(identity.clone()).await?;
+
+        let mut guard = self.identity.lock().await;
+        if *guard != identity {
+            let _ = self.broker_channel.try_send(());
+        }
+        *guard = identity;
+
+        log::info!("Assigned identity");
+        Ok(())
+    }
+
+    /// Persist a certificate or key to connect to brokers over TLS with, and reconnect using it.
+    pub async fn provision_tls(
+        &self,
+        slot: tls::Slot,
+        der: Option<tls::Der>,
+    ) -> Result<(), storage::Error> {
+        self.tls_credentials
+            .lock()
+            .await
+            .provision(self.storage, slot, der)
+            .await?;
+        let _ = self.broker_channel.try_send(());
+
+        log::info!("Provisioned {:?}", slot);
+        Ok(())
+    }
+
+    pub async fn identity(&self) -> Identity {
+        self.identity.lock().await.clone()
+    }
+
+    /// Metrics of connecting to the broker.
+    pub async fn connection(&self) -> ConnectionReport {
+        self.metrics.lock().await.report()
+    }
+}
+
+/// Try to send a message with when receiving an unrelated packet, retry until we get an Ack.
+async fn send_message_qos1<T: Read + Write>(
+    client: &mut MqttClient<'_, T, MAX_PROPERTIES, CountingRng>,
+    topic: &str,
+    content: &[u8],
+    retain: bool,
+) -> Result<(), ReasonCode> {
+    // TODO fix the MQTT client to not be lossy.
+
+    for _ in 0..5 {
+        match client
+            .send_message(topic, content, QualityOfService::QoS1, retain)
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(ReasonCode::ImplementationSpecificError) => {
+                continue;
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+    Err(ReasonCode::ImplementationSpecificError)
+}
+
+async fn mqtt_connected<T: Read + Write>(
+    system: &'static Net,
+    watchdog_ticket: &WatchdogTicket,
+    client: &mut MqttClient<'_, T, MAX_PROPERTIES, CountingRng>,
+) -> Disconnect {
+    use embassy_futures::select::{select3, Either3};
+
+    loop {
+        watchdog_ticket.feed().await;
+
+        let outcoming_fut = system.outbox.pop();
+        let incoming_fut = client.receive_message();
+        let broker_fut = system.broker_channel.receive();
+
+        match select3(outcoming_fut, incoming_fut, broker_fut).await {
+            Either3::First(message) => {
+                match send_message_qos1(client, &message.topic, &message.content, message.retain)
+                    .await
+                {
+                    Ok(()) => {}
+                    Err(ReasonCode::NetworkError) => {
+                        // Send it once we are connected again.
+                        system.outbox.requeue(message);
+                        log::error!("Network error");
+                        return Disconnect::Network;
+                    }
+                    Err(e) => log::error!("{:?}", e),
+                }
+            }
+            Either3::Second(message) => match message {
+                Ok((topic, buf)) => {
+                    if let Some(reply) = system.process_message(topic, buf).await {
+                        if let Err(e) =
+                            send_message_qos1(client, &reply.topic, &reply.content, reply.retain)
+                                .await
+                        {
+                            log::error!("{:?}", e);
+                        }
+                    }
+                }
+                Err(ReasonCode::ImplementationSpecificError) => {}
+                Err(ReasonCode::NetworkError) => {
+                    log::error!("Network error");
+                    return Disconnect::Network;
+                }
+                Err(e) => log::error!("{:?}", e),
+            },
+            Either3::Third(()) => {
+                log::info!("Reconnecting to apply new settings");
+                let _ = client.disconnect().await;
+                return Disconnect::Reconfigured;
+            }
+        }
+    }
+}
+
+/// Communicate with a broker over an established connection, until the connection ends.
+///
+/// Fails when the broker refuses the connection.
+async fn mqtt_session<T: Read + Write>(
+    system: &'static Net,
+    watchdog_ticket: &WatchdogTicket,
+    connection: T,
+    settings: &BrokerSettings,
+    seed: u64,
+) -> Result<Disconnect, Failure> {
+    // Note(unwrap): the topic is small enough to always fit.
+    let availability = Topic::Availability.to_str().unwrap();
+
+    let mut config: ClientConfig<'_, MAX_PROPERTIES, CountingRng> = ClientConfig::new(
+        rust_mqtt::client::client_config::MqttVersion::MQTTv5,
+        CountingRng(seed % (u16::MAX as u64)),
+    );
+
+    config.add_max_subscribe_qos(QualityOfService::QoS1);
+    config.add_client_id(&settings.client_id);
+    config.keep_alive = settings.keepalive_secs;
+    if let Some(username) = &settings.username {
+        config.add_username(username);
+    }
+    if let Some(password) = &settings.password {
+        config.add_password(password);
+    }
+    config.max_packet_size = MAX_PACKET_SIZE as u32;
+    // Retained, such that this device is not presumed alive when it vanishes.
+    config.add_will(&availability, b"offline", true);
+
+    let mut recv_buffer = [0; MAX_PACKET_SIZE];
+    let mut write_buffer = [0; MAX_PACKET_SIZE];
+
+    let mut client = MqttClient::new(
+        connection,
+        &mut write_buffer,
+        MAX_PACKET_SIZE,
+        &mut recv_buffer,
+        MAX_PACKET_SIZE,
+        config,
+    );
+
+    log::info!("Connecting to broker...");
+    client.connect_to_broker().await?;
+    log::info!("Broker connected");
+
+    let identity = system.identity().await;
+    // Only commands are subscribed to, as other topics carry our own state.
+    for prefix in identity.prefixes() {
+        let mut topic = prefix;
+        topic.push_str("/cmd/#").unwrap();
+        if let Err(e) = client.subscribe_to_topic(&topic).await {
+            log::warn!("Failed to subscribe to \"{}\": {:?}", topic, e);
+            let _ = client.disconnect().await;
+            return Err(e.into());
+        }
+    }
+
+    if let Err(e) = send_message_qos1(&mut client, &availability, b"online", true).await {
+        log::error!("{:?}", e);
+    }
+
+    system.metrics.lock().await.connected();
+    system.event_channel.publish_immediate(Event::ConnectedMQTT);
+
+    for message in discovery::Node::new(&identity).announcements() {
+        if let Err(e) = send_message_qos1(
+            &mut client,
+            &message.topic,
+            &message.content,
+            message.retain,
+        )
+        .await
+        {
+            log::error!("{:?}", e);
+        }
+    }
+
+    Ok(mqtt_connected(system, watchdog_ticket, &mut client).await)
+}
+
+/// Wait before connecting again, or until new settings are provisioned, in which case this yields true.
+async fn back_off(system: &'static Net, watchdog_ticket: &WatchdogTicket, delay: Duration) -> bool {
+    use embassy_futures::select::{select, Either};
+
+    system
+        .event_channel
+        .publish_immediate(Event::BackingOff { delay });
+
+    let until = Instant::now() + delay;
+    while Instant::now() < until {
+        watchdog_ticket.feed().await;
+
+        let deadline = until.min(Instant::now() + WATCHDOG_DEADLINE);
+        if let Either::Second(()) =
+            select(Timer::at(deadline), system.broker_channel.receive()).await
+        {
+            log::info!("Connecting right away with new settings");
+            return true;
+        }
+    }
+    false
+}
+
+async fn link_up(
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    system: &'static Net,
+    seed: u64,
+    mut rng: HardwareRng,
+    tls_buffers: &mut RecordBuffers,
+    watchdog_ticket: &WatchdogTicket,
+) {
+    system.event_channel.publish_immediate(Event::ConnectedWifi);
+    watchdog_ticket.feed().await;
+
+    let mut rx_buffer = [0; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0; SOCKET_BUFFER_SIZE];
+
+    // Index of the broker to try next, advancing whenever one is unreachable.
+    let mut index = 0;
+    let mut backoff = Backoff::default();
+    // Whether all brokers tried in this pass refused us for reasons that will not resolve by themselves.
+    let mut permanent = true;
+
+    loop {
+        if !stack.is_link_up() {
+            log::warn!("Link down, awaiting reconnect...");
+            return;
+        }
+
+        let settings = system.broker_settings.lock().await.clone();
+        if settings.brokers.is_empty() {
+            log::warn!("No brokers configured, awaiting provisioning");
+            system.broker_channel.receive().await;
+            continue;
+        }
+        if index >= settings.brokers.len() {
+            let delay = backoff.next(permanent, &mut rng);
+            log::warn!(
+                "All brokers unreachable, retrying in {}s...",
+                delay.as_secs()
+            );
+            if back_off(system, watchdog_ticket, delay).await {
+                backoff.reset();
+            }
+            index = 0;
+            permanent = true;
+            continue;
+        }
+        let broker = &settings.brokers[index];
+        watchdog_ticket.feed().await;
+
+        system.metrics.lock().await.attempt();
+        system
+            .event_channel
+            .publish_immediate(Event::ConnectingMQTT { broker: index });
+
+        let result: Result<Disconnect, Failure> = async {
+            log::info!("Resolving broker \"{}\"...", broker.host);
+            let address = broker::resolve(stack, broker).await.map_err(|e| {
+                log::warn!("Failed to resolve \"{}\": {:?}", broker.host, e);
+                Failure::Dns
+            })?;
+
+            let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+
+            socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
+
+            let endpoint = IpEndpoint::new(address, broker.port);
+
+            log::info!("Connecting to socket {}...", endpoint);
+            socket.connect(endpoint).await.map_err(|e| {
+                log::info!("connect error: {:?}", e);
+                Failure::Socket
+            })?;
+            log::info!("Socket connected!");
+
+            if broker.plaintext {
+                log::warn!("Connecting without TLS");
+                return mqtt_session(system, watchdog_ticket, socket, &settings, seed).await;
+            }
+
+            let mut connection: TlsConnection<_, tls::CipherSuite> =
+                TlsConnection::new(socket, &mut tls_buffers.read, &mut tls_buffers.write);
+
+            {
+                let credentials = system.tls_credentials.lock().await;
+                let config = credentials.config(&broker.host).ok_or_else(|| {
+                    log::error!("No CA certificate to authenticate \"{}\" with", broker.host);
+                    Failure::NoCaCertificate
+                })?;
+
+                log::info!("Negotiating TLS...");
+                let context = TlsContext::new(&config, tls::Provider::new(rng));
+                connection.open(context).await.map_err(|e| {
+                    log::warn!("TLS handshake failed: {:?}", e);
+                    Failure::Tls
+                })?;
+            }
+
+            mqtt_session(system, watchdog_ticket, connection, &settings, seed).await
+        }
+        .await;
+
+        match result {
+            Ok(reason) => {
+                let session = system.metrics.lock().await.disconnected();
+                log::warn!(
+                    "MQTT connection ended after {}s: {:?}",
+                    session.as_secs(),
+                    reason
+                );
+                system
+                    .event_channel
+                    .publish_immediate(Event::DisconnectedMQTT { reason, session });
+
+                // Start over at the most preferred broker, unless it was short-lived.
+                index = 0;
+                permanent = true;
+                if let Some(delay) = backoff.disconnected(reason, session, &mut rng) {
+                    if back_off(system, watchdog_ticket, delay).await {
+                        backoff.reset();
+                    }
+                }
+            }
+            Err(failure) => {
+                log::warn!("Failed to connect to \"{}\": {:?}", broker.host, failure);
+                system.metrics.lock().await.failed(failure);
+                system.event_channel.publish_immediate(Event::FailedMQTT {
+                    broker: index,
+                    failure,
+                });
+
+                permanent &= failure.is_permanent();
+                index += 1;
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn net_task(
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    system: &'static Net,
+    seed: u64,
+    rng: HardwareRng,
+    tls_buffers: &'static mut RecordBuffers,
+    watchdog_ticket: WatchdogTicket,
+) {
+    loop {
+        loop {
+            if stack.is_link_up() {
+                break;
+            }
+            Timer::after(Duration::from_millis(50)).await;
+        }
+
+        log::info!("Waiting to get IP address...");
+        loop {
+            if let Some(config) = stack.config_v4() {
+                log::info!("Got IP: {}", config.address);
+                break;
+            }
+            Timer::after(Duration::from_millis(50)).await;
+        }
+
+        watchdog_ticket.feed().await;
+
+        link_up(stack, system, seed, rng, tls_buffers, &watchdog_ticket).await;
+
+        system
+            .event_channel
+            .publish_immediate(Event::DisconnectedWifi);
+    }
+}
+
+#[embassy_executor::task]
+async fn connection_task(mut controller: WifiController<'static>, system: &'static Net) {
+    use embassy_futures::select::{select, Either};
+
+    log::info!("start connection task");
+    log::info!("Device capabilities: {:?}", controller.get_capabilities());
+    loop {
+        if let WifiState::StaConnected = esp_wifi::wifi::get_wifi_state() {
+            // wait until we're no longer connected, or until we have to use new credentials
+            match select(
+                controller.wait_for_event(WifiEvent::StaDisconnected),
+                system.wifi_channel.receive(),
+            )
+            .await
+            {
+                Either::First(_) => Timer::after(Duration::from_millis(1000)).await,
+                Either::Second(_) => {
+                    log::info!("Reconnecting with new credentials");
+                    if let Err(e) = controller.disconnect().await {
+                        log::warn!("Failed to disconnect from wifi: {e:?}");
+                    }
+                }
+            }
+        }
+
+        let Some(credentials) = system.wifi_credentials.lock().await.clone() else {
+            log::warn!("No WiFi credentials, awaiting provisioning");
+            system.wifi_channel.receive().await;
+            continue;
+        };
+
+        let client_config = Configuration::Client(ClientConfiguration {
+            ssid: credentials.ssid.as_str().try_into().unwrap(),
+            password: credentials.password.as_str().try_into().unwrap(),
+            ..Default::default()
+        });
+        controller.set_configuration(&client_config).unwrap();
+
+        if !matches!(controller.is_started(), Ok(true)) {
+            log::info!("Starting wifi");
+            controller.start().await.unwrap();
+            log::info!("Wifi started!");
+        }
+        log::info!("About to connect to \"{}\"...", credentials.ssid);
+
+        match controller.connect().await {
+            Ok(_) => log::info!("Wifi connected!"),
+            Err(e) => {
+                log::info!("Failed to connect to wifi: {e:?}");
+                // Retry later, or right away when provisioned with other credentials.
+                select(
+                    Timer::after(Duration::from_millis(5000)),
+                    system.wifi_channel.receive(),
+                )
+                .await;
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn journal_task(system: &'static Net) {
+    loop {
+        system.journal_channel.receive().await;
+
+        match system.config.journal().await {
+            Ok(changes) => {
+                for change in changes.oldest_ordered() {
+                    system
+                        .send(Message::new(&Topic::Journal, change).unwrap())
+                        .await;
+                }
+            }
+            Err(e) => log::error!("Failed to fetch journal: {:?}", e),
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn stack_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
+    stack.run().await;
+}
@@ -0,0 +1,362 @@
+//! Store-and-forward queue of outgoing messages, bridging the periods in which we are disconnected.
+
+use core::{cell::RefCell, fmt::Write, future::poll_fn, task::Poll};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    waitqueue::{MultiWakerRegistration, WakerRegistration},
+};
+use heapless::{String, Vec};
+
+use super::message::Message;
+
+const OUTBOX_SIZE: usize = 16;
+/// Slots shared by all messages but records, keeping one slot free for a record,
+/// and one for the message being sent to be requeued when sending fails.
+const SHARED_SIZE: usize = OUTBOX_SIZE - 2;
+
+/// How a message is treated when it cannot be sent right away, from least to most important.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Priority {
+    /// Measurements, of which only the newest is kept.
+    Telemetry,
+    /// State, of which only the newest is kept.
+    State,
+    /// Records, of which only the newest is kept, in a slot of its own such that it is never dropped.
+    Record,
+    /// Every message is kept, making room by dropping less important messages.
+    /// When there is none to drop, the publisher waits for room.
+    Alert,
+}
+
+impl Priority {
+    /// Whether an older message to the same topic is superseded by a newer one.
+    fn coalesces(self) -> bool {
+        self != Priority::Alert
+    }
+
+    /// Whether the message may be dropped to make room for a more important one.
+    fn droppable(self) -> bool {
+        self <= Priority::State
+    }
+}
+
+struct Inner {
+    queue: Vec<Message, OUTBOX_SIZE>,
+    receiver: WakerRegistration,
+    /// Publishers of alerts waiting for room.
+    senders: MultiWakerRegistration<4>,
+}
+
+impl Inner {
+    /// Whether there is room for `message`, making room by dropping a less important message when needed.
+    fn make_room(&mut self, message: &Message) -> bool {
+        // A record always fits, as all other messages leave a slot free for it.
+        if message.priority == Priority::Record || self.superseded(message).is_some() {
+            return true;
+        }
+
+        let shared = self
+            .queue
+            .iter()
+            .filter(|queued| queued.priority != Priority::Record)
+            .count();
+        if shared < SHARED_SIZE {
+            return true;
+        }
+
+        // Drop the oldest of the least important messages, but never for a less important message.
+        let victim = self
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, queued)| {
+                queued.priority.droppable() && queued.priority <= message.priority
+            })
+            .min_by_key(|(_, queued)| (queued.priority, queued.created))
+            .map(|(i, _)| i);
+
+        match victim {
+            Some(i) => {
+                let dropped = self.queue.remove(i);
+                log::warn!("Outbox full, dropped message to {}", dropped.topic);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Index of the queued message that `message` supersedes, if any.
+    fn superseded(&self, message: &Message) -> Option<usize> {
+        if !message.priority.coalesces() {
+            return None;
+        }
+        self.queue
+            .iter()
+            .position(|queued| queued.topic == message.topic)
+    }
+
+    /// Queue a message, for which there must be room, see [Inner::make_room].
+    fn insert(&mut self, message: Message) {
+        match self.superseded(&message) {
+            // A requeued message might be older than the one superseding it.
+            Some(i) => {
+                if message.created >= self.queue[i].created {
+                    self.queue[i] = message;
+                }
+            }
+            // Note(unwrap): room was made beforehand.
+            None => self.queue.push(message).ok().unwrap(),
+        }
+    }
+
+    /// Take the most important message, the oldest first.
+    fn take(&mut self) -> Option<Message> {
+        let (i, _) = self
+            .queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, queued)| (core::cmp::Reverse(queued.priority), queued.created))?;
+        self.senders.wake();
+        Some(self.queue.remove(i))
+    }
+}
+
+pub struct Outbox {
+    inner: Mutex<CriticalSectionRawMutex, RefCell<Inner>>,
+}
+
+impl Outbox {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                queue: Vec::new(),
+                receiver: WakerRegistration::new(),
+                senders: MultiWakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// Queue a message, dropping a less important message when there is no room.
+    ///
+    /// Telemetry and state are dropped themselves when there is no room, such that
+    /// their producers are not held up while we are disconnected. An alert waits for room instead.
+    pub async fn push(&self, message: Message) {
+        let mut message = Some(message);
+        poll_fn(|cx| {
+            self.inner.lock(|inner| {
+                let mut inner = inner.borrow_mut();
+                // Note(unwrap): only taken once ready.
+                let pending = message.as_ref().unwrap();
+                if inner.make_room(pending) {
+                    inner.insert(message.take().unwrap());
+                    inner.receiver.wake();
+                    Poll::Ready(())
+                } else if pending.priority == Priority::Alert {
+                    inner.senders.register(cx.waker());
+                    Poll::Pending
+                } else {
+                    log::warn!("Outbox full, dropped message to {}", pending.topic);
+                    Poll::Ready(())
+                }
+            })
+        })
+        .await
+    }
+
+    /// Queue the message taken last, which could not be sent after all.
+    ///
+    /// Always fits, as pushed messages leave a slot free for it.
+    pub fn requeue(&self, message: Message) {
+        self.inner.lock(|inner| inner.borrow_mut().insert(message))
+    }
+
+    /// Wait for the next message to send.
+    pub async fn pop(&self) -> Message {
+        poll_fn(|cx| {
+            self.inner.lock(|inner| {
+                let mut inner = inner.borrow_mut();
+                match inner.take() {
+                    Some(message) => Poll::Ready(message),
+                    None => {
+                        inner.receiver.register(cx.waker());
+                        Poll::Pending
+                    }
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Add the moment at which a JSON object was created to it as `uptime_ms`.
+///
+/// Messages can be sent long after their creation, hence receivers cannot rely on the moment of reception.
+pub fn stamp(message: &mut Message) {
+    if message.content.first() != Some(&b'{') || message.content.last() != Some(&b'}') {
+        return;
+    }
+
+    let mut field: String<32> = String::new();
+    let separator = if message.content.len() > 2 { "," } else { "" };
+    // Note(unwrap): a u64 always fits.
+    write!(
+        field,
+        "{}\"uptime_ms\":{}}}",
+        separator,
+        message.created.as_millis()
+    )
+    .unwrap();
+
+    message.content.pop();
+    if message.content.extend_from_slice(field.as_bytes()).is_err() {
+        // Leave the message as it was, without the timestamp.
+        message.content.push(b'}').unwrap();
+        log::warn!("No room to stamp message to {}", message.topic);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+
+    use embassy_futures::{block_on, poll_once};
+    use embassy_time::Instant;
+
+    use super::*;
+
+    fn message(topic: &str, priority: Priority, created: u64) -> Message {
+        Message {
+            topic: topic.try_into().unwrap(),
+            content: Vec::from_slice(topic.as_bytes()).unwrap(),
+            retain: false,
+            priority,
+            created: Instant::from_millis(created),
+        }
+    }
+
+    fn drain(outbox: &Outbox) -> std::vec::Vec<(std::string::String, u64)> {
+        core::iter::from_fn(|| outbox.inner.lock(|inner| inner.borrow_mut().take()))
+            .map(|message| (message.topic.as_str().into(), message.created.as_millis()))
+            .collect()
+    }
+
+    #[test]
+    fn coalesces_all_but_alerts() {
+        let outbox = Outbox::new();
+        block_on(async {
+            outbox.push(message("stats", Priority::Telemetry, 1)).await;
+            outbox.push(message("stats", Priority::Telemetry, 2)).await;
+            outbox.push(message("config", Priority::State, 3)).await;
+            outbox.push(message("config", Priority::State, 4)).await;
+            outbox.push(message("record", Priority::Record, 5)).await;
+            outbox.push(message("record", Priority::Record, 6)).await;
+            outbox.push(message("journal", Priority::Alert, 7)).await;
+            outbox.push(message("journal", Priority::Alert, 8)).await;
+        });
+
+        assert_eq!(
+            drain(&outbox),
+            [
+                ("journal".into(), 7),
+                ("journal".into(), 8),
+                ("record".into(), 6),
+                ("config".into(), 4),
+                ("stats".into(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn requeued_message_does_not_supersede_newer() {
+        let outbox = Outbox::new();
+        block_on(outbox.push(message("config", Priority::State, 1)));
+        let taken = block_on(outbox.pop());
+        block_on(outbox.push(message("config", Priority::State, 2)));
+        outbox.requeue(taken);
+
+        assert_eq!(drain(&outbox), [("config".into(), 2)]);
+    }
+
+    #[test]
+    fn takes_most_important_first() {
+        let outbox = Outbox::new();
+        block_on(async {
+            outbox.push(message("stats", Priority::Telemetry, 1)).await;
+            outbox.push(message("config", Priority::State, 2)).await;
+            outbox.push(message("record", Priority::Record, 3)).await;
+            outbox.push(message("error", Priority::Alert, 4)).await;
+            outbox.push(message("warning", Priority::Alert, 5)).await;
+        });
+
+        assert_eq!(
+            drain(&outbox),
+            [
+                ("error".into(), 4),
+                ("warning".into(), 5),
+                ("record".into(), 3),
+                ("config".into(), 2),
+                ("stats".into(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_outbox_drops_least_important() {
+        let outbox = Outbox::new();
+        block_on(async {
+            for i in 0..SHARED_SIZE as u64 {
+                outbox
+                    .push(message(&format!("state/{i}"), Priority::State, i))
+                    .await;
+            }
+            // No room, and nothing less important to drop.
+            outbox
+                .push(message("stats", Priority::Telemetry, 100))
+                .await;
+            // Drops the oldest state.
+            outbox.push(message("error", Priority::Alert, 101)).await;
+        });
+
+        let sent = drain(&outbox);
+        assert_eq!(sent.len(), SHARED_SIZE);
+        assert_eq!(sent[0], ("error".into(), 101));
+        assert_eq!(sent[1], ("state/1".into(), 1));
+    }
+
+    #[test]
+    fn full_outbox_keeps_records_and_alerts() {
+        let outbox = Outbox::new();
+        block_on(async {
+            for i in 0..SHARED_SIZE as u64 {
+                outbox.push(message("journal", Priority::Alert, i)).await;
+            }
+            // A record has a slot of its own.
+            outbox.push(message("record", Priority::Record, 100)).await;
+            // Nothing may be dropped for these.
+            outbox.push(message("config", Priority::State, 101)).await;
+        });
+
+        // An alert waits for room, rather than dropping another alert.
+        let mut pending = pin!(outbox.push(message("warning", Priority::Alert, 102)));
+        assert!(poll_once(pending.as_mut()).is_pending());
+
+        // The message taken while waiting fits back in when it could not be sent.
+        let taken = block_on(outbox.pop());
+        assert!(poll_once(pending.as_mut()).is_ready());
+        outbox.requeue(taken);
+
+        let sent = drain(&outbox);
+        assert_eq!(sent.len(), SHARED_SIZE + 2);
+        assert!(sent[..SHARED_SIZE + 1]
+            .iter()
+            .all(|(topic, _)| topic == "journal" || topic == "warning"));
+        assert_eq!(sent[SHARED_SIZE + 1], ("record".into(), 100));
+    }
+}
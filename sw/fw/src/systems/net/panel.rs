@@ -0,0 +1,162 @@
+//! Control panel for browsers, embedded in flash, along with the live values it streams.
+
+use embassy_futures::select::{select3, Either3};
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_io_async::Write;
+use portable_atomic::{AtomicUsize, Ordering};
+use serde::Serialize;
+
+use crate::{
+    systems::{power_ext, record::Record, stats::Stats},
+    util::{Milliamps, Millivolts},
+};
+
+use super::{http::json, Net};
+
+pub const PAGE: &[u8] = include_bytes!("panel.html");
+
+/// Maximum number of clients streaming events at the same time, each taking a subscriber of the systems streamed.
+pub const MAX_STREAMS: usize = 2;
+
+/// Interval at which the settings are checked for changes.
+///
+/// These are polled instead of subscribed to, as a slow client would otherwise hold up changing them.
+const CONFIG_POLL: Duration = Duration::from_millis(500);
+
+/// Time without events after which a comment is sent, detecting clients that went away.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+static STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// Power negotiated over USB-PD, and the bounds of the settings following from it.
+#[derive(Serialize)]
+pub struct Power {
+    contract: Option<ContractReport>,
+    vout_min_mv: Millivolts,
+    vout_max_mv: Millivolts,
+    iout_max_ma: Milliamps,
+    power_max_mw: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ContractReport {
+    voltage_mv: Millivolts,
+    current_ma: Milliamps,
+    power_mw: u32,
+}
+
+impl Power {
+    pub async fn new(net: &Net) -> Self {
+        let contract = net.usbpd.contract().await;
+        let limits = power_ext::limits(contract);
+
+        Self {
+            contract: contract.map(|contract| ContractReport {
+                voltage_mv: contract.voltage,
+                current_ma: contract.current,
+                power_mw: contract.power_mw(),
+            }),
+            vout_min_mv: *limits.vout.start(),
+            vout_max_mv: *limits.vout.end(),
+            iout_max_ma: limits.iout_max,
+            power_max_mw: limits.power_max_mw,
+        }
+    }
+}
+
+/// Claim on one of the [MAX_STREAMS], released when dropped.
+pub struct Stream(());
+
+impl Stream {
+    pub fn open() -> Option<Self> {
+        if STREAMS.fetch_add(1, Ordering::Relaxed) >= MAX_STREAMS {
+            STREAMS.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Stream(()))
+    }
+
+    /// Send the current values and all changes thereof as Server-Sent Events, until the client goes away.
+    pub async fn run(
+        self,
+        socket: &mut TcpSocket<'_>,
+        net: &'static Net,
+        stats: Option<&'static Stats>,
+        record: &'static Record,
+    ) -> Result<(), tcp::Error> {
+        let mut stats_subscriber = stats.map(|stats| stats.subscriber());
+        let mut record_subscriber = record.subscriber();
+        let mut ticker = Ticker::every(CONFIG_POLL);
+
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")
+            .await?;
+
+        let latest = match stats {
+            Some(stats) => stats.latest_data().await,
+            None => None,
+        };
+        if let Some(data) = latest {
+            send(socket, "stats", &data).await?;
+        }
+        send(socket, "record", &record.fetch().await).await?;
+        let mut settings = net.config.fetch().await;
+        send(socket, "config", &settings).await?;
+        let mut last_sent = Instant::now();
+
+        loop {
+            match select3(
+                async {
+                    match stats_subscriber.as_mut() {
+                        Some(subscriber) => subscriber.next_message_pure().await,
+                        None => core::future::pending().await,
+                    }
+                },
+                record_subscriber.next_message_pure(),
+                ticker.next(),
+            )
+            .await
+            {
+                Either3::First(data) => send(socket, "stats", &data).await?,
+                Either3::Second(data) => send(socket, "record", &data).await?,
+                Either3::Third(()) => {
+                    let current = net.config.fetch().await;
+                    if current != settings {
+                        settings = current;
+                        send(socket, "config", &settings).await?;
+                    } else if last_sent.elapsed() >= KEEPALIVE {
+                        socket.write_all(b": keepalive\n\n").await?;
+                        socket.flush().await?;
+                    } else {
+                        continue;
+                    }
+                }
+            }
+            last_sent = Instant::now();
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        STREAMS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn send(
+    socket: &mut TcpSocket<'_>,
+    event: &str,
+    value: &impl Serialize,
+) -> Result<(), tcp::Error> {
+    let Some(data) = json(value) else {
+        return Ok(());
+    };
+
+    socket.write_all(b"event: ").await?;
+    socket.write_all(event.as_bytes()).await?;
+    socket.write_all(b"\ndata: ").await?;
+    socket.write_all(&data).await?;
+    socket.write_all(b"\n\n").await?;
+    socket.flush().await
+}
@@ -0,0 +1,133 @@
+//! Routing of received messages by their topic.
+
+use heapless::Vec;
+
+/// Maximum number of `+` wildcards in a pattern.
+const MAX_WILDCARDS: usize = 4;
+
+/// Levels of a topic that matched the wildcards of a pattern.
+#[derive(Debug, Default)]
+pub struct Captures<'a> {
+    /// Levels matched by each `+`, in order.
+    levels: Vec<&'a str, MAX_WILDCARDS>,
+}
+
+impl<'a> Captures<'a> {
+    pub fn level(&self, index: usize) -> Option<&'a str> {
+        self.levels.get(index).copied()
+    }
+}
+
+/// Match `topic` against an MQTT topic filter, which can contain `+` to match a single level and a trailing `#` to match any remaining levels.
+pub fn matches<'a>(pattern: &str, topic: &'a str) -> Option<Captures<'a>> {
+    let mut captures = Captures::default();
+    let mut remainder = Some(topic);
+
+    for expected in pattern.split('/') {
+        if expected == "#" {
+            // Also matches the parent level itself.
+            return Some(captures);
+        }
+
+        let (level, tail) = match remainder?.split_once('/') {
+            Some((level, tail)) => (level, Some(tail)),
+            None => (remainder?, None),
+        };
+        match expected {
+            "+" => captures.levels.push(level).ok()?,
+            expected if expected == level => {}
+            _ => return None,
+        }
+        remainder = tail;
+    }
+
+    remainder.is_none().then_some(captures)
+}
+
+/// Table of handlers by topic pattern, relative to the namespace of the device.
+pub struct Router<H, const N: usize> {
+    routes: Vec<(&'static str, H), N>,
+}
+
+#[derive(Debug)]
+pub struct RouterFull;
+
+impl<H, const N: usize> Router<H, N> {
+    pub const fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Register `handler` for the topics matching `pattern`.
+    ///
+    /// Patterns are tried in the order in which they were registered, hence register specific patterns before more generic ones.
+    pub fn register(&mut self, pattern: &'static str, handler: H) -> Result<(), RouterFull> {
+        self.routes.push((pattern, handler)).map_err(|_| RouterFull)
+    }
+
+    /// Find the handler for `topic`, along with the levels matching the wildcards of its pattern.
+    pub fn route<'a>(&self, topic: &'a str) -> Option<(&H, Captures<'a>)> {
+        self.routes
+            .iter()
+            .find_map(|(pattern, handler)| Some((handler, matches(pattern, topic)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels<'a>(captures: Option<Captures<'a>>) -> Option<std::vec::Vec<&'a str>> {
+        captures.map(|captures| captures.levels.into_iter().collect())
+    }
+
+    #[test]
+    fn matches_exact_topic() {
+        assert_eq!(levels(matches("cmd/journal", "cmd/journal")), Some(vec![]));
+        assert!(matches("cmd/journal", "cmd/journals").is_none());
+        assert!(matches("cmd/journal", "cmd").is_none());
+        assert!(matches("cmd/journal", "cmd/journal/get").is_none());
+        assert!(matches("cmd/journal", "cmd/journal/").is_none());
+    }
+
+    #[test]
+    fn matches_single_level_wildcard() {
+        assert_eq!(
+            levels(matches("cmd/preset/+", "cmd/preset/save")),
+            Some(vec!["save"])
+        );
+        assert_eq!(
+            levels(matches("+/tls/+", "cmd/tls/ca")),
+            Some(vec!["cmd", "ca"])
+        );
+        // An empty level is a level nonetheless.
+        assert_eq!(
+            levels(matches("cmd/preset/+", "cmd/preset/")),
+            Some(vec![""])
+        );
+        assert!(matches("cmd/preset/+", "cmd/preset").is_none());
+        assert!(matches("cmd/preset/+", "cmd/preset/save/now").is_none());
+    }
+
+    #[test]
+    fn matches_multi_level_wildcard() {
+        assert_eq!(levels(matches("cmd/#", "cmd")), Some(vec![]));
+        assert_eq!(levels(matches("cmd/#", "cmd/tls/ca")), Some(vec![]));
+        assert_eq!(levels(matches("#", "cmd/tls/ca")), Some(vec![]));
+        assert_eq!(levels(matches("+/tls/#", "cmd/tls/ca")), Some(vec!["cmd"]));
+        assert!(matches("cmd/#", "config").is_none());
+        assert!(matches("cmd/#", "cmdx/tls").is_none());
+    }
+
+    #[test]
+    fn routes_to_first_registered() {
+        let mut router = Router::<u8, 2>::new();
+        router.register("cmd/preset/list", 0).unwrap();
+        router.register("cmd/preset/+", 1).unwrap();
+        assert!(router.register("cmd/#", 2).is_err());
+
+        assert_eq!(router.route("cmd/preset/list").map(|(h, _)| *h), Some(0));
+        let (handler, captures) = router.route("cmd/preset/save").unwrap();
+        assert_eq!((*handler, captures.level(0)), (1, Some("save")));
+        assert!(router.route("cmd/journal").is_none());
+    }
+}
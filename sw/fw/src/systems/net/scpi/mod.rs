@@ -0,0 +1,300 @@
+//! SCPI over a raw TCP socket, for lab automation frameworks.
+//!
+//! Commands are terminated by a newline, and their replies are as well. Errors are not replied, but queued to be
+//! retrieved by `SYSTem:ERRor?`.
+//!
+//! As anyone on the network can connect, changes are protected until unlocked for the session by sending the command
+//! token with `SYSTem:PASSword`.
+
+use core::fmt::Write as _;
+
+use embassy_executor::Spawner;
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use heapless::{Deque, String};
+
+use crate::systems::{
+    config::{Rejection, Settings, Source, UpdateError},
+    power_ext::{self, PowerExt},
+    stats::Stats,
+};
+
+use super::{
+    commands,
+    discovery::{FIRMWARE_VERSION, MANUFACTURER, MODEL},
+    identity,
+    mdns::Service,
+    Net, SOCKET_BUFFER_SIZE,
+};
+
+mod parser;
+
+use parser::{Command, Error, ErrorReply, Milli};
+
+pub const PORT: u16 = 5025;
+
+/// Longest program message, excluding its terminator.
+const LINE_SIZE: usize = 256;
+/// Longest reply to a single query.
+const REPLY_SIZE: usize = 64;
+const ERROR_QUEUE_SIZE: usize = 8;
+
+/// Interval at which an idle client is probed, and the time after which it is dropped when not responding.
+const KEEPALIVE: Duration = Duration::from_secs(10);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bit of the questionable status register set during overcurrent.
+const QUESTIONABLE_CURRENT: u16 = 1 << 1;
+
+type Reply = String<REPLY_SIZE>;
+
+/// Errors in the order they occurred, where the last is replaced when overflowing.
+struct ErrorQueue(Deque<Error, ERROR_QUEUE_SIZE>);
+
+impl ErrorQueue {
+    fn push(&mut self, error: Error) {
+        log::warn!("SCPI error: {:?}", error);
+        if self.0.is_full() {
+            self.0.pop_back();
+            // Note(unwrap): an entry was just removed.
+            self.0.push_back(Error::QueueOverflow).unwrap();
+        } else {
+            // Note(unwrap): checked for space above.
+            self.0.push_back(error).unwrap();
+        }
+    }
+}
+
+impl From<UpdateError> for Error {
+    fn from(error: UpdateError) -> Self {
+        match error {
+            UpdateError::Rejected(Rejection::PowerExceedsContract { .. }) => {
+                Error::SettingsConflict
+            }
+            UpdateError::Rejected(_) => Error::DataOutOfRange,
+            UpdateError::Storage(_) => Error::MassStorage,
+        }
+    }
+}
+
+pub struct Scpi;
+
+impl Scpi {
+    /// Without stats and the power stage, measurements are reported as stale and the status registers as clear.
+    pub fn init(
+        net: &'static Net,
+        stats: Option<&'static Stats>,
+        power_ext: Option<&'static PowerExt>,
+        spawner: &Spawner,
+    ) {
+        spawner.must_spawn(scpi_task(net, stats, power_ext));
+        net.services.advertise(Service::Scpi);
+    }
+}
+
+struct Session {
+    net: &'static Net,
+    stats: Option<&'static Stats>,
+    power_ext: Option<&'static PowerExt>,
+    errors: ErrorQueue,
+    /// Whether changes were unlocked with the command token.
+    unlocked: bool,
+}
+
+impl Session {
+    /// Execute a program message, replying to its queries, if any.
+    async fn process(&mut self, socket: &mut TcpSocket<'_>, line: &str) -> Result<(), tcp::Error> {
+        let mut replied = false;
+        for command in parser::parse(line) {
+            let result = match command {
+                Ok(command) => self.execute(command).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(Some(reply)) => {
+                    // Replies to multiple queries are separated like the queries themselves.
+                    if replied {
+                        socket.write_all(b";").await?;
+                    }
+                    socket.write_all(reply.as_bytes()).await?;
+                    replied = true;
+                }
+                Ok(None) => {}
+                // Remaining commands are not executed, as they may depend on the one that failed.
+                Err(e) => {
+                    self.errors.push(e);
+                    break;
+                }
+            }
+        }
+
+        if replied {
+            socket.write_all(b"\n").await?;
+            socket.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn execute(&mut self, command: Command<'_>) -> Result<Option<Reply>, Error> {
+        let mut reply = Reply::new();
+        // Note(unwrap): all replies fit the reply buffer.
+        match command {
+            Command::Identify => write!(
+                reply,
+                "{},{},{},{}",
+                MANUFACTURER,
+                MODEL,
+                identity::serial(),
+                FIRMWARE_VERSION
+            )
+            .unwrap(),
+            Command::Reset => {
+                // The power-on policy is a preference rather than part of the state of the output.
+                self.update(|settings| {
+                    *settings = Settings {
+                        power_on: settings.power_on,
+                        ..Settings::default()
+                    }
+                })
+                .await?;
+                return Ok(None);
+            }
+            Command::ClearStatus => {
+                self.errors.0.clear();
+                return Ok(None);
+            }
+            Command::SetVoltage(vout_mv) => {
+                self.update(|settings| settings.vout_mv = vout_mv).await?;
+                return Ok(None);
+            }
+            Command::Voltage => {
+                let settings = self.net.config.fetch().await;
+                write!(reply, "{}", Milli(settings.vout_mv.0 as u32)).unwrap();
+            }
+            Command::SetCurrent(iout_ma) => {
+                self.update(|settings| settings.iout_ma = iout_ma).await?;
+                return Ok(None);
+            }
+            Command::Current => {
+                let settings = self.net.config.fetch().await;
+                write!(reply, "{}", Milli(settings.iout_ma.0 as u32)).unwrap();
+            }
+            Command::SetOutput(enabled) => {
+                self.update(|settings| settings.output_enabled = enabled)
+                    .await?;
+                return Ok(None);
+            }
+            Command::Output => {
+                let settings = self.net.config.fetch().await;
+                write!(reply, "{}", settings.output_enabled as u8).unwrap();
+            }
+            Command::MeasureVoltage => {
+                let stats = self.stats.ok_or(Error::DataStale)?;
+                let data = stats.latest_data().await.ok_or(Error::DataStale)?;
+                write!(reply, "{}", Milli(data.vout_mv.0 as u32)).unwrap();
+            }
+            Command::Questionable => {
+                let condition = match self.power_ext {
+                    Some(power_ext) => match power_ext.state().await {
+                        power_ext::State::Ocp => QUESTIONABLE_CURRENT,
+                        _ => 0,
+                    },
+                    None => 0,
+                };
+                write!(reply, "{}", condition).unwrap();
+            }
+            Command::NextError => {
+                write!(reply, "{}", ErrorReply(self.errors.0.pop_front())).unwrap();
+            }
+            Command::Unlock(token) => {
+                if !commands::is_command_token(token) {
+                    return Err(Error::IllegalParameterValue);
+                }
+                self.unlocked = true;
+                return Ok(None);
+            }
+        }
+        Ok(Some(reply))
+    }
+
+    async fn update(&self, f: impl FnOnce(&mut Settings)) -> Result<(), Error> {
+        if !self.unlocked {
+            return Err(Error::CommandProtected);
+        }
+        Ok(self.net.config.update(Source::Scpi, f).await?)
+    }
+
+    /// Serve program messages until the client goes away.
+    async fn serve(&mut self, socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+        let mut buffer = [0; LINE_SIZE];
+        let mut len = 0;
+        // Whether the remainder of an overly long line is being discarded.
+        let mut overrun = false;
+
+        loop {
+            let read = socket.read(&mut buffer[len..]).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            len += read;
+
+            while let Some(end) = buffer[..len].iter().position(|b| *b == b'\n') {
+                if overrun {
+                    overrun = false;
+                } else {
+                    match core::str::from_utf8(&buffer[..end]) {
+                        Ok(line) => self.process(socket, line.trim_end_matches('\r')).await?,
+                        Err(_) => self.errors.push(Error::Syntax),
+                    }
+                }
+                buffer.copy_within(end + 1..len, 0);
+                len -= end + 1;
+            }
+
+            if len == buffer.len() {
+                self.errors.push(Error::InputBufferOverrun);
+                overrun = true;
+                len = 0;
+            }
+        }
+    }
+}
+
+/// Serves a single client at a time, as instruments usually do.
+#[embassy_executor::task]
+async fn scpi_task(
+    net: &'static Net,
+    stats: Option<&'static Stats>,
+    power_ext: Option<&'static PowerExt>,
+) {
+    let mut rx_buffer = [0; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0; SOCKET_BUFFER_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(net.stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_keep_alive(Some(KEEPALIVE));
+        socket.set_timeout(Some(TIMEOUT));
+
+        if let Err(e) = socket.accept(PORT).await {
+            log::warn!("Failed to accept SCPI connection: {:?}", e);
+            continue;
+        }
+        log::info!("SCPI client connected");
+
+        let mut session = Session {
+            net,
+            stats,
+            power_ext,
+            errors: ErrorQueue(Deque::new()),
+            unlocked: false,
+        };
+        if let Err(e) = session.serve(&mut socket).await {
+            log::info!("SCPI session ended: {:?}", e);
+        }
+
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
@@ -0,0 +1,598 @@
+//! Parsing of SCPI program messages, independent of the systems they act upon.
+//!
+//! Headers match by either their short or long form, case-insensitively. Optional nodes like `SOURce` and
+//! `LEVel` are accepted anywhere, being more lenient than SCPI requires.
+
+use core::fmt::{self, Display};
+
+use heapless::Vec;
+
+use crate::util::{Milliamps, Millivolts};
+
+/// Deepest header supported, counting the optional nodes.
+const MAX_LEVELS: usize = 6;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Command<'a> {
+    /// `*IDN?`
+    Identify,
+    /// `*RST`
+    Reset,
+    /// `*CLS`
+    ClearStatus,
+    /// `VOLTage <volts>`
+    SetVoltage(Millivolts),
+    /// `VOLTage?`
+    Voltage,
+    /// `CURRent <amps>`
+    SetCurrent(Milliamps),
+    /// `CURRent?`
+    Current,
+    /// `OUTPut ON|OFF`
+    SetOutput(bool),
+    /// `OUTPut?`
+    Output,
+    /// `MEASure:VOLTage?`
+    MeasureVoltage,
+    /// `STATus:QUEStionable?`
+    Questionable,
+    /// `SYSTem:ERRor?`
+    NextError,
+    /// `SYSTem:PASSword "<token>"`
+    Unlock(&'a str),
+}
+
+/// Errors as numbered by SCPI.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Error {
+    Syntax,
+    DataType,
+    ParameterNotAllowed,
+    MissingParameter,
+    UndefinedHeader,
+    InvalidSuffix,
+    /// A change was attempted before unlocking changes with the command token.
+    CommandProtected,
+    SettingsConflict,
+    DataOutOfRange,
+    IllegalParameterValue,
+    /// No measurements were taken yet.
+    DataStale,
+    /// The settings could not be persisted.
+    MassStorage,
+    QueueOverflow,
+    InputBufferOverrun,
+}
+
+impl Error {
+    pub fn code(&self) -> i16 {
+        match self {
+            Error::Syntax => -102,
+            Error::DataType => -104,
+            Error::ParameterNotAllowed => -108,
+            Error::MissingParameter => -109,
+            Error::UndefinedHeader => -113,
+            Error::InvalidSuffix => -131,
+            Error::CommandProtected => -203,
+            Error::SettingsConflict => -221,
+            Error::DataOutOfRange => -222,
+            Error::IllegalParameterValue => -224,
+            Error::DataStale => -230,
+            Error::MassStorage => -250,
+            Error::QueueOverflow => -350,
+            Error::InputBufferOverrun => -363,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Error::Syntax => "Syntax error",
+            Error::DataType => "Data type error",
+            Error::ParameterNotAllowed => "Parameter not allowed",
+            Error::MissingParameter => "Missing parameter",
+            Error::UndefinedHeader => "Undefined header",
+            Error::InvalidSuffix => "Invalid suffix",
+            Error::CommandProtected => "Command protected",
+            Error::SettingsConflict => "Settings conflict",
+            Error::DataOutOfRange => "Data out of range",
+            Error::IllegalParameterValue => "Illegal parameter value",
+            Error::DataStale => "Data corrupt or stale",
+            Error::MassStorage => "Mass storage error",
+            Error::QueueOverflow => "Queue overflow",
+            Error::InputBufferOverrun => "Input buffer overrun",
+        }
+    }
+}
+
+/// Entry of the error queue as queried by `SYSTem:ERRor?`, where none is reported as `0,"No error"`.
+pub struct ErrorReply(pub Option<Error>);
+
+impl Display for ErrorReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(error) => write!(f, "{},\"{}\"", error.code(), error.description()),
+            None => f.write_str("0,\"No error\""),
+        }
+    }
+}
+
+/// Quantity in thousandths, formatted in its base unit as SCPI expects, like `5.000` for 5000mV.
+pub struct Milli(pub u32);
+
+impl Display for Milli {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:03}", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mnemonic {
+    Source,
+    Voltage,
+    Current,
+    Output,
+    State,
+    Measure,
+    Status,
+    Questionable,
+    Event,
+    Condition,
+    System,
+    Error,
+    Next,
+    Password,
+    Cenable,
+    Level,
+    Immediate,
+    Amplitude,
+    Dc,
+}
+
+impl Mnemonic {
+    /// Mnemonics by their long form, of which the uppercase part is the short form.
+    const TABLE: [(&'static str, Mnemonic); 19] = [
+        ("SOURce", Mnemonic::Source),
+        ("VOLTage", Mnemonic::Voltage),
+        ("CURRent", Mnemonic::Current),
+        ("OUTPut", Mnemonic::Output),
+        ("STATe", Mnemonic::State),
+        ("MEASure", Mnemonic::Measure),
+        ("STATus", Mnemonic::Status),
+        ("QUEStionable", Mnemonic::Questionable),
+        ("EVENt", Mnemonic::Event),
+        ("CONDition", Mnemonic::Condition),
+        ("SYSTem", Mnemonic::System),
+        ("ERRor", Mnemonic::Error),
+        ("NEXT", Mnemonic::Next),
+        ("PASSword", Mnemonic::Password),
+        ("CENable", Mnemonic::Cenable),
+        ("LEVel", Mnemonic::Level),
+        ("IMMediate", Mnemonic::Immediate),
+        ("AMPLitude", Mnemonic::Amplitude),
+        ("DC", Mnemonic::Dc),
+    ];
+
+    fn parse(level: &str) -> Option<Self> {
+        Self::TABLE.iter().find_map(|(long, mnemonic)| {
+            let short = long.trim_end_matches(|c: char| c.is_ascii_lowercase());
+            (level.eq_ignore_ascii_case(long) || level.eq_ignore_ascii_case(short))
+                .then_some(*mnemonic)
+        })
+    }
+
+    /// Whether the node can be left out, as it is implied when absent.
+    fn is_optional(&self) -> bool {
+        matches!(
+            self,
+            Mnemonic::Source
+                | Mnemonic::State
+                | Mnemonic::Event
+                | Mnemonic::Condition
+                | Mnemonic::Next
+                | Mnemonic::Cenable
+                | Mnemonic::Level
+                | Mnemonic::Immediate
+                | Mnemonic::Amplitude
+                | Mnemonic::Dc
+        )
+    }
+}
+
+/// Parse a program message, being one or more commands separated by `;`.
+pub fn parse(message: &str) -> impl Iterator<Item = Result<Command<'_>, Error>> + '_ {
+    message
+        .split(';')
+        .map(str::trim)
+        .filter(|command| !command.is_empty())
+        .map(parse_command)
+}
+
+pub fn parse_command(command: &str) -> Result<Command<'_>, Error> {
+    let (header, parameter) = match command.split_once(|c: char| c.is_ascii_whitespace()) {
+        Some((header, parameter)) => (header, Some(parameter.trim())),
+        None => (command, None),
+    };
+    let (header, query) = match header.strip_suffix('?') {
+        Some(header) => (header, true),
+        None => (header, false),
+    };
+
+    if header.starts_with('*') {
+        let command = match query {
+            true if header.eq_ignore_ascii_case("*IDN") => Command::Identify,
+            false if header.eq_ignore_ascii_case("*RST") => Command::Reset,
+            false if header.eq_ignore_ascii_case("*CLS") => Command::ClearStatus,
+            _ => return Err(Error::UndefinedHeader),
+        };
+        return without_parameter(command, parameter);
+    }
+
+    let mut levels: Vec<Mnemonic, MAX_LEVELS> = Vec::new();
+    for (index, level) in header
+        .strip_prefix(':')
+        .unwrap_or(header)
+        .split(':')
+        .enumerate()
+    {
+        if level.is_empty() {
+            return Err(Error::Syntax);
+        }
+        let mnemonic = match Mnemonic::parse(level).ok_or(Error::UndefinedHeader)? {
+            // Both have `STAT` as short form, but only the status subsystem is at the root.
+            Mnemonic::State if index == 0 => Mnemonic::Status,
+            mnemonic => mnemonic,
+        };
+        if !mnemonic.is_optional() {
+            levels.push(mnemonic).map_err(|_| Error::UndefinedHeader)?;
+        }
+    }
+
+    match (levels.as_slice(), query) {
+        ([Mnemonic::Voltage], false) => Ok(Command::SetVoltage(Millivolts(quantity(
+            required(parameter)?,
+            "V",
+        )?))),
+        ([Mnemonic::Voltage], true) => without_parameter(Command::Voltage, parameter),
+        ([Mnemonic::Current], false) => Ok(Command::SetCurrent(Milliamps(quantity(
+            required(parameter)?,
+            "A",
+        )?))),
+        ([Mnemonic::Current], true) => without_parameter(Command::Current, parameter),
+        ([Mnemonic::Output], false) => Ok(Command::SetOutput(boolean(required(parameter)?)?)),
+        ([Mnemonic::Output], true) => without_parameter(Command::Output, parameter),
+        ([Mnemonic::Measure, Mnemonic::Voltage], true) => {
+            without_parameter(Command::MeasureVoltage, parameter)
+        }
+        ([Mnemonic::Status, Mnemonic::Questionable], true) => {
+            without_parameter(Command::Questionable, parameter)
+        }
+        ([Mnemonic::System, Mnemonic::Error], true) => {
+            without_parameter(Command::NextError, parameter)
+        }
+        ([Mnemonic::System, Mnemonic::Password], false) => {
+            Ok(Command::Unlock(string(required(parameter)?)?))
+        }
+        _ => Err(Error::UndefinedHeader),
+    }
+}
+
+fn required(parameter: Option<&str>) -> Result<&str, Error> {
+    parameter
+        .filter(|parameter| !parameter.is_empty())
+        .ok_or(Error::MissingParameter)
+}
+
+fn without_parameter<'a>(
+    command: Command<'a>,
+    parameter: Option<&str>,
+) -> Result<Command<'a>, Error> {
+    match parameter {
+        Some(parameter) if !parameter.is_empty() => Err(Error::ParameterNotAllowed),
+        _ => Ok(command),
+    }
+}
+
+fn boolean(parameter: &str) -> Result<bool, Error> {
+    if parameter.eq_ignore_ascii_case("ON") || parameter == "1" {
+        Ok(true)
+    } else if parameter.eq_ignore_ascii_case("OFF") || parameter == "0" {
+        Ok(false)
+    } else {
+        Err(Error::DataType)
+    }
+}
+
+/// Parse string data, enclosed in either single or double quotes.
+///
+/// The enclosing quote cannot occur within the string, as SCPI would have it doubled.
+fn string(parameter: &str) -> Result<&str, Error> {
+    let quote = match parameter.chars().next() {
+        Some(quote @ ('"' | '\'')) => quote,
+        _ => return Err(Error::DataType),
+    };
+    match parameter[1..].strip_suffix(quote) {
+        Some(content) if !content.contains(quote) => Ok(content),
+        _ => Err(Error::DataType),
+    }
+}
+
+/// Parse a decimal number in `unit`, optionally suffixed by the unit with or without the milli prefix,
+/// yielding it in thousandths of that unit.
+///
+/// Uses fixed point arithmetic, rounding to the nearest thousandth.
+fn quantity(parameter: &str, unit: &str) -> Result<u16, Error> {
+    let number_end = parameter
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | 'e' | 'E')))
+        .unwrap_or(parameter.len());
+    let (number, suffix) = parameter.split_at(number_end);
+    if number.is_empty() {
+        return Err(Error::DataType);
+    }
+
+    let mut scale: i32 = match suffix.trim() {
+        "" => 3,
+        suffix if suffix.eq_ignore_ascii_case(unit) => 3,
+        suffix
+            if suffix.len() == unit.len() + 1
+                && suffix[..1].eq_ignore_ascii_case("M")
+                && suffix[1..].eq_ignore_ascii_case(unit) =>
+        {
+            0
+        }
+        _ => return Err(Error::InvalidSuffix),
+    };
+
+    let (mantissa, exponent) = match number.find(['e', 'E']) {
+        Some(index) => (&number[..index], Some(&number[index + 1..])),
+        None => (number, None),
+    };
+    if let Some(exponent) = exponent {
+        let exponent: i32 = exponent.parse().map_err(|_| Error::DataType)?;
+        scale = scale.saturating_add(exponent);
+    }
+
+    let (negative, mantissa) = match mantissa.as_bytes().first() {
+        Some(b'-') => (true, &mantissa[1..]),
+        Some(b'+') => (false, &mantissa[1..]),
+        _ => (false, mantissa),
+    };
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if integer.is_empty() && fraction.is_empty() {
+        return Err(Error::DataType);
+    }
+
+    let mut value: u64 = 0;
+    for c in integer.chars().chain(fraction.chars()) {
+        let digit = c.to_digit(10).ok_or(Error::DataType)?;
+        // Beyond this, the value is out of range anyway unless scaled down by an absurd exponent.
+        value = value
+            .checked_mul(10)
+            .and_then(|value| value.checked_add(digit as u64))
+            .filter(|value| *value < 1 << 48)
+            .ok_or(Error::DataOutOfRange)?;
+    }
+    scale = scale.saturating_sub(fraction.len() as i32);
+
+    let value = if value == 0 {
+        0
+    } else if scale >= 0 {
+        10u64
+            .checked_pow(scale as u32)
+            .and_then(|factor| value.checked_mul(factor))
+            .ok_or(Error::DataOutOfRange)?
+    } else {
+        match 10u64.checked_pow(scale.unsigned_abs()) {
+            Some(divisor) => (value + divisor / 2) / divisor,
+            None => 0,
+        }
+    };
+
+    if negative && value != 0 {
+        return Err(Error::DataOutOfRange);
+    }
+    value.try_into().map_err(|_| Error::DataOutOfRange)
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::*;
+
+    fn parse_all(message: &str) -> Vec<Result<Command<'_>, Error>, 8> {
+        parse(message).collect()
+    }
+
+    #[test]
+    fn headers_match_short_and_long_form() {
+        for header in ["VOLT", "VOLTage", "volt", "voltage", "VoLtAgE"] {
+            let command = [header, " 5"].concat();
+            assert_eq!(
+                parse_command(&command),
+                Ok(Command::SetVoltage(Millivolts(5000))),
+                "{}",
+                command
+            );
+        }
+
+        // Neither the short nor the long form.
+        assert_eq!(parse_command("VOL 5"), Err(Error::UndefinedHeader));
+        assert_eq!(parse_command("VOLTA 5"), Err(Error::UndefinedHeader));
+        assert_eq!(parse_command("VOLTages 5"), Err(Error::UndefinedHeader));
+    }
+
+    #[test]
+    fn optional_nodes_are_implied() {
+        assert_eq!(
+            parse_command("SOUR:VOLT:LEV:IMM:AMPL 5"),
+            Ok(Command::SetVoltage(Millivolts(5000)))
+        );
+        assert_eq!(
+            parse_command(":SOURce:CURRent 0.5"),
+            Ok(Command::SetCurrent(Milliamps(500)))
+        );
+        assert_eq!(
+            parse_command("OUTP:STAT OFF"),
+            Ok(Command::SetOutput(false))
+        );
+        assert_eq!(parse_command("MEAS:VOLT:DC?"), Ok(Command::MeasureVoltage));
+        assert_eq!(parse_command("SYST:ERR:NEXT?"), Ok(Command::NextError));
+        // `STAT` at the root is the status subsystem rather than the state.
+        assert_eq!(parse_command("STAT:QUES:COND?"), Ok(Command::Questionable));
+    }
+
+    #[test]
+    fn queries() {
+        assert_eq!(parse_command("*IDN?"), Ok(Command::Identify));
+        assert_eq!(parse_command("*idn?"), Ok(Command::Identify));
+        assert_eq!(parse_command("VOLT?"), Ok(Command::Voltage));
+        assert_eq!(parse_command("CURR?"), Ok(Command::Current));
+        assert_eq!(parse_command("OUTP?"), Ok(Command::Output));
+        assert_eq!(parse_command("MEAS:VOLT?"), Ok(Command::MeasureVoltage));
+        assert_eq!(
+            parse_command("STATus:QUEStionable?"),
+            Ok(Command::Questionable)
+        );
+        assert_eq!(parse_command("SYSTem:ERRor?"), Ok(Command::NextError));
+
+        // Only some headers are queries, and only some are commands.
+        assert_eq!(parse_command("*RST?"), Err(Error::UndefinedHeader));
+        assert_eq!(parse_command("*IDN"), Err(Error::UndefinedHeader));
+        assert_eq!(parse_command("MEAS:VOLT 5"), Err(Error::UndefinedHeader));
+    }
+
+    #[test]
+    fn quantities_with_suffixes() {
+        let voltage = |command: &str| {
+            parse_command(command).map(|command| match command {
+                Command::SetVoltage(Millivolts(mv)) => mv,
+                command => panic!("Unexpected {:?}", command),
+            })
+        };
+
+        assert_eq!(voltage("VOLT 5V"), Ok(5000));
+        assert_eq!(voltage("VOLT 5 v"), Ok(5000));
+        assert_eq!(voltage("VOLT 3300mV"), Ok(3300));
+        assert_eq!(voltage("VOLT 3300 MV"), Ok(3300));
+        assert_eq!(voltage("VOLT 5e-1"), Ok(500));
+        assert_eq!(voltage("VOLT 0.05E2"), Ok(5000));
+        assert_eq!(voltage("VOLT +.5"), Ok(500));
+        // Rounded to the nearest millivolt.
+        assert_eq!(voltage("VOLT 1.2345"), Ok(1235));
+        assert_eq!(voltage("VOLT 1.2344"), Ok(1234));
+        assert_eq!(voltage("VOLT -0"), Ok(0));
+
+        assert_eq!(voltage("VOLT 5A"), Err(Error::InvalidSuffix));
+        assert_eq!(voltage("VOLT 5kV"), Err(Error::InvalidSuffix));
+        assert_eq!(
+            parse_command("CURR 100mA"),
+            Ok(Command::SetCurrent(Milliamps(100)))
+        );
+        assert_eq!(parse_command("CURR 1V"), Err(Error::InvalidSuffix));
+    }
+
+    #[test]
+    fn booleans() {
+        assert_eq!(parse_command("OUTP ON"), Ok(Command::SetOutput(true)));
+        assert_eq!(parse_command("OUTP on"), Ok(Command::SetOutput(true)));
+        assert_eq!(parse_command("OUTP 1"), Ok(Command::SetOutput(true)));
+        assert_eq!(parse_command("OUTP 0"), Ok(Command::SetOutput(false)));
+        assert_eq!(parse_command("OUTP 2"), Err(Error::DataType));
+        assert_eq!(parse_command("OUTP maybe"), Err(Error::DataType));
+    }
+
+    #[test]
+    fn passwords() {
+        assert_eq!(
+            parse_command("SYST:PASS \"secret\""),
+            Ok(Command::Unlock("secret"))
+        );
+        assert_eq!(
+            parse_command("SYSTem:PASSword:CENable 'a secret'"),
+            Ok(Command::Unlock("a secret"))
+        );
+        assert_eq!(parse_command("SYST:PASS \"\""), Ok(Command::Unlock("")));
+
+        assert_eq!(parse_command("SYST:PASS secret"), Err(Error::DataType));
+        assert_eq!(parse_command("SYST:PASS \"secret"), Err(Error::DataType));
+        assert_eq!(parse_command("SYST:PASS \""), Err(Error::DataType));
+        assert_eq!(parse_command("SYST:PASS 'sec'ret'"), Err(Error::DataType));
+        assert_eq!(parse_command("SYST:PASS"), Err(Error::MissingParameter));
+        assert_eq!(parse_command("SYST:PASS?"), Err(Error::UndefinedHeader));
+    }
+
+    #[test]
+    fn compound_commands() {
+        assert_eq!(
+            parse_all("VOLT 5;CURR 0.1; OUTP ON;;"),
+            [
+                Ok(Command::SetVoltage(Millivolts(5000))),
+                Ok(Command::SetCurrent(Milliamps(100))),
+                Ok(Command::SetOutput(true)),
+            ]
+        );
+        assert_eq!(
+            parse_all("VOLT?;CURR?"),
+            [Ok(Command::Voltage), Ok(Command::Current)]
+        );
+        // Every command is parsed by itself, stopping at the first error is up to the session.
+        assert_eq!(
+            parse_all("*CLS;FOO;OUTP OFF"),
+            [
+                Ok(Command::ClearStatus),
+                Err(Error::UndefinedHeader),
+                Ok(Command::SetOutput(false)),
+            ]
+        );
+        assert_eq!(parse_all(""), []);
+        assert_eq!(parse_all(" ; "), []);
+    }
+
+    #[test]
+    fn malformed_commands() {
+        assert_eq!(parse_command("VOLT::LEV 5"), Err(Error::Syntax));
+        assert_eq!(parse_command("VOLT: 5"), Err(Error::Syntax));
+        assert_eq!(parse_command("VOLT"), Err(Error::MissingParameter));
+        assert_eq!(parse_command("OUTP "), Err(Error::MissingParameter));
+        assert_eq!(parse_command("VOLT? 5"), Err(Error::ParameterNotAllowed));
+        assert_eq!(parse_command("*RST 1"), Err(Error::ParameterNotAllowed));
+        assert_eq!(parse_command("VOLT abc"), Err(Error::DataType));
+        assert_eq!(parse_command("VOLT ."), Err(Error::DataType));
+        assert_eq!(parse_command("VOLT 1.2.3"), Err(Error::DataType));
+        assert_eq!(parse_command("VOLT 5e"), Err(Error::DataType));
+        assert_eq!(parse_command("VOLT -1"), Err(Error::DataOutOfRange));
+        assert_eq!(parse_command("VOLT 70"), Err(Error::DataOutOfRange));
+        assert_eq!(parse_command("VOLT 1e100"), Err(Error::DataOutOfRange));
+        assert_eq!(
+            parse_command("VOLT 99999999999999999999"),
+            Err(Error::DataOutOfRange)
+        );
+        assert_eq!(parse_command("FOO:BAR?"), Err(Error::UndefinedHeader));
+        assert_eq!(
+            parse_command("SOUR:SOUR:SOUR:SOUR:SOUR:SOUR:SOUR:VOLT 5"),
+            Ok(Command::SetVoltage(Millivolts(5000)))
+        );
+        assert_eq!(
+            parse_command("MEAS:MEAS:MEAS:MEAS:MEAS:MEAS:MEAS?"),
+            Err(Error::UndefinedHeader)
+        );
+    }
+
+    #[test]
+    fn formats_replies() {
+        let format = |value: &dyn Display| {
+            let mut reply: String<32> = String::new();
+            core::fmt::write(&mut reply, format_args!("{}", value)).unwrap();
+            reply
+        };
+
+        assert_eq!(format(&Milli(5000)), "5.000");
+        assert_eq!(format(&Milli(1234)), "1.234");
+        assert_eq!(format(&Milli(50)), "0.050");
+        assert_eq!(format(&ErrorReply(None)), "0,\"No error\"");
+        assert_eq!(
+            format(&ErrorReply(Some(Error::Syntax))),
+            "-102,\"Syntax error\""
+        );
+    }
+}
@@ -0,0 +1,264 @@
+//! Connecting to brokers over TLS 1.3.
+//!
+//! Brokers are authenticated by a CA certificate, and this device optionally by a client certificate.
+//! Both are stored in flash, falling back to those given at build time.
+
+use derive_more::{From, Into};
+use embedded_tls::{
+    webpki::CertVerifier, Aes128GcmSha256, Certificate, CryptoProvider, SignatureScheme, TlsClock,
+    TlsConfig, TlsError, TlsVerifier,
+};
+use esp_hal::rng::Rng;
+use heapless::Vec;
+use p256::{
+    ecdsa::{signature::SignerMut, DerSignature, SigningKey},
+    SecretKey,
+};
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::systems::storage::{self, MaxSize, Storage, StorageEntry, StorageKey};
+
+use super::MAX_PACKET_SIZE;
+
+/// Certificates and key in DER, encoded in hex, to use until others have been provisioned.
+const DEFAULT_CA: Option<&str> = option_env!("PSU_MQTT_CA");
+const DEFAULT_CLIENT_CERTIFICATE: Option<&str> = option_env!("PSU_MQTT_CLIENT_CERT");
+const DEFAULT_CLIENT_KEY: Option<&str> = option_env!("PSU_MQTT_CLIENT_KEY");
+
+/// Largest certificate or key, such that it fits a single storage entry.
+pub const MAX_DER_SIZE: usize = 1536;
+
+/// Largest certificate of a broker that can be verified.
+const MAX_SERVER_CERTIFICATE_SIZE: usize = 4096;
+
+/// Records can be up to 16 KiB plus overhead, as most brokers do not support negotiating a smaller maximum.
+const READ_RECORD_SIZE: usize = 16640;
+/// Records we write are never larger than an MQTT packet plus overhead.
+const WRITE_RECORD_SIZE: usize = MAX_PACKET_SIZE + 256;
+
+pub type CipherSuite = Aes128GcmSha256;
+
+pub type Der = Vec<u8, MAX_DER_SIZE>;
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, From, Into)]
+pub struct CaCertificate(Der);
+
+impl MaxSize for CaCertificate {
+    const MAX_SIZE: usize = Der::MAX_SIZE;
+}
+
+impl StorageEntry for CaCertificate {
+    const KEY: StorageKey = StorageKey::CaCertificate;
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, From, Into)]
+pub struct ClientCertificate(Der);
+
+impl MaxSize for ClientCertificate {
+    const MAX_SIZE: usize = Der::MAX_SIZE;
+}
+
+impl StorageEntry for ClientCertificate {
+    const KEY: StorageKey = StorageKey::ClientCertificate;
+}
+
+/// Private key belonging to the [ClientCertificate], being a SEC1 encoded P-256 key.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, From, Into)]
+pub struct ClientKey(Der);
+
+impl MaxSize for ClientKey {
+    const MAX_SIZE: usize = Der::MAX_SIZE;
+}
+
+impl StorageEntry for ClientKey {
+    const KEY: StorageKey = StorageKey::ClientKey;
+}
+
+/// Which of the [Credentials] to provision.
+#[derive(Debug, Clone, Copy)]
+pub enum Slot {
+    Ca,
+    ClientCertificate,
+    ClientKey,
+}
+
+impl Slot {
+    /// Slot by the last level of the topic of the provisioning command.
+    pub fn from_level(level: &str) -> Option<Self> {
+        match level {
+            "ca" => Some(Slot::Ca),
+            "cert" => Some(Slot::ClientCertificate),
+            "key" => Some(Slot::ClientKey),
+            _ => None,
+        }
+    }
+}
+
+/// Decode a certificate or key in DER from hex.
+pub fn decode(hex: &str) -> Option<Der> {
+    let mut der = Der::new();
+    der.resize_default(hex.len() / 2).ok()?;
+    hex::decode_to_slice(hex, &mut der).ok()?;
+    Some(der)
+}
+
+pub struct Credentials {
+    ca: Option<Der>,
+    certificate: Option<Der>,
+    key: Option<Der>,
+}
+
+impl Credentials {
+    pub async fn fetch(storage: &Storage) -> Self {
+        Self {
+            ca: fetch::<CaCertificate>(storage, DEFAULT_CA).await,
+            certificate: fetch::<ClientCertificate>(storage, DEFAULT_CLIENT_CERTIFICATE).await,
+            key: fetch::<ClientKey>(storage, DEFAULT_CLIENT_KEY).await,
+        }
+    }
+
+    /// Persist a certificate or key, where clearing it reverts to the one given at build time, if any.
+    pub async fn provision(
+        &mut self,
+        storage: &Storage,
+        slot: Slot,
+        der: Option<Der>,
+    ) -> Result<(), storage::Error> {
+        // Stored empty when cleared, as entries cannot be removed.
+        let stored = der.clone().unwrap_or_default();
+        match slot {
+            Slot::Ca => {
+                storage.store(CaCertificate(stored)).await?;
+                self.ca = der.or_else(|| DEFAULT_CA.and_then(decode));
+            }
+            Slot::ClientCertificate => {
+                storage.store(ClientCertificate(stored)).await?;
+                self.certificate = der.or_else(|| DEFAULT_CLIENT_CERTIFICATE.and_then(decode));
+            }
+            Slot::ClientKey => {
+                storage.store(ClientKey(stored)).await?;
+                self.key = der.or_else(|| DEFAULT_CLIENT_KEY.and_then(decode));
+            }
+        }
+        Ok(())
+    }
+
+    /// Configuration to connect to `server_name`, which is only possible when we have a CA certificate.
+    pub fn config<'a>(&'a self, server_name: &'a str) -> Option<TlsConfig<'a>> {
+        let mut config = TlsConfig::new()
+            .with_server_name(server_name)
+            .with_ca(Certificate::X509(self.ca.as_ref()?));
+
+        // Client authentication is optional, but requires both the certificate and its key.
+        if let (Some(certificate), Some(key)) = (&self.certificate, &self.key) {
+            config = config
+                .with_cert(Certificate::X509(certificate))
+                .with_priv_key(key);
+        }
+        Some(config)
+    }
+}
+
+async fn fetch<T: StorageEntry + Into<Der>>(
+    storage: &Storage,
+    default: Option<&str>,
+) -> Option<Der> {
+    let stored = match storage.fetch::<T>().await {
+        Ok(entry) => entry.map(Into::into).filter(|der: &Der| !der.is_empty()),
+        Err(e) => {
+            log::error!("Failed to fetch {:?}: {:?}", T::KEY, e);
+            None
+        }
+    };
+    stored.or_else(|| default.and_then(decode))
+}
+
+/// Buffers for the records of a TLS connection, which are too large to keep on the stack of a task.
+pub struct RecordBuffers {
+    pub read: [u8; READ_RECORD_SIZE],
+    pub write: [u8; WRITE_RECORD_SIZE],
+}
+
+impl RecordBuffers {
+    pub const fn new() -> Self {
+        Self {
+            read: [0; READ_RECORD_SIZE],
+            write: [0; WRITE_RECORD_SIZE],
+        }
+    }
+}
+
+/// The hardware random number generator, which is only truly random while the radio is enabled.
+///
+/// As we only use TLS while connected to WiFi, that is always the case.
+#[derive(Clone, Copy)]
+pub struct HardwareRng(pub Rng);
+
+impl RngCore for HardwareRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.0.random() as u64) << 32 | self.0.random() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.read(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for HardwareRng {}
+
+/// We lack a wall clock, hence the validity period of certificates is not checked.
+struct NoClock;
+
+impl TlsClock for NoClock {
+    fn now() -> Option<u64> {
+        None
+    }
+}
+
+pub struct Provider {
+    rng: HardwareRng,
+    verifier: CertVerifier<CipherSuite, NoClock, MAX_SERVER_CERTIFICATE_SIZE>,
+}
+
+impl Provider {
+    pub fn new(rng: HardwareRng) -> Self {
+        Self {
+            rng,
+            verifier: CertVerifier::new(),
+        }
+    }
+}
+
+impl CryptoProvider for Provider {
+    type CipherSuite = CipherSuite;
+    type Signature = DerSignature;
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+
+    fn signer(
+        &mut self,
+        key_der: &[u8],
+    ) -> Result<(impl SignerMut<Self::Signature>, SignatureScheme), TlsError> {
+        let key = SecretKey::from_sec1_der(key_der).map_err(|_| TlsError::InvalidPrivateKey)?;
+        Ok((
+            SigningKey::from(&key),
+            SignatureScheme::EcdsaSecp256r1Sha256,
+        ))
+    }
+}
@@ -2,19 +2,21 @@
 
 use embassy_executor::SendSpawner;
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, pubsub::WaitResult,
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex,
+    pubsub::WaitResult,
 };
 use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rtc_cntl::SocResetReason;
 use serde::Serialize;
 use static_cell::StaticCell;
 
 use crate::{
     bsp::{self, I2cBusDevice, I2cError},
-    drivers::tps55289::{ll::Tps55289, IntFB, VRef},
+    drivers::tps55289::{ll::Tps55289, IntFB, IoutLimit, VRef},
     systems::{
-        config::{Config, Settings},
+        config::{Config, Limits, PowerOn, Settings, Source},
         record::Record,
-        usb_pd::Usbpd,
+        usb_pd::{Contract, Usbpd},
         watchdog::{self, Watchdog, WatchdogTicket},
     },
 };
@@ -28,20 +30,65 @@ pub enum State {
     Ocp,
 }
 
+type NotifyChannel = Channel<CriticalSectionRawMutex, (), 1>;
+
 struct Inner {
     ll: Tps55289<I2cBusDevice, I2cError>,
     backoff_duration: Duration,
+    output_enabled: bool,
     state: State,
 }
 
 pub struct PowerExt {
     inner: Mutex<CriticalSectionRawMutex, Inner>,
+    settings_notifier: NotifyChannel,
     usbpd: &'static Usbpd,
     record: &'static Record,
     watchdog: WatchdogTicket,
 }
 
 const FEEDBACK: IntFB = IntFB::Ratio0_0564;
+const CURRENT_SENSE_MILLIOHM: u32 = 20;
+
+/// What the converter is capable of, given the contract with the input supply.
+pub fn limits(contract: Option<Contract>) -> Limits {
+    Limits {
+        vout: FEEDBACK.output_range(),
+        iout_max: IoutLimit::max_current(CURRENT_SENSE_MILLIOHM),
+        power_max_mw: contract.map(|c| c.power_mw()),
+    }
+}
+
+/// Whether the output should be enabled after powering on.
+fn power_on_output(settings: &Settings, reset_reason: Option<SocResetReason>) -> bool {
+    match settings.power_on {
+        PowerOn::Off => false,
+        PowerOn::Restore => {
+            // Something went wrong, hence do not blindly re-enable the output.
+            let fault = matches!(
+                reset_reason,
+                Some(
+                    SocResetReason::CoreMwdt0
+                        | SocResetReason::CoreMwdt1
+                        | SocResetReason::CoreRtcWdt
+                        | SocResetReason::Cpu0Mwdt0
+                        | SocResetReason::Cpu0Mwdt1
+                        | SocResetReason::Cpu0RtcWdt
+                        | SocResetReason::SysRtcWdt
+                        | SocResetReason::SysSuperWdt
+                        | SocResetReason::SysBrownOut
+                        | SocResetReason::SysClkGlitch
+                        | SocResetReason::CorePwrGlitch
+                )
+            );
+            if fault && settings.output_enabled {
+                log::warn!("Keeping output disabled after {:?}", reset_reason);
+            }
+            settings.output_enabled && !fault
+        }
+        PowerOn::On => true,
+    }
+}
 
 impl PowerExt {
     pub async fn init(
@@ -50,6 +97,7 @@ impl PowerExt {
         record: &'static Record,
         config: &'static Config,
         watchdog: &'static Watchdog,
+        reset_reason: Option<SocResetReason>,
         spawner: &SendSpawner,
     ) -> &'static Self {
         bsp.enable_pin.set_high();
@@ -73,14 +121,37 @@ impl PowerExt {
             inner: Mutex::new(Inner {
                 ll,
                 backoff_duration: Duration::default(), // placeholder value until persist
+                output_enabled: false,
                 state: State::Disabled,
             }),
+            settings_notifier: NotifyChannel::new(),
             usbpd,
             record,
             watchdog: watchdog.ticket().await,
         });
 
-        system.persist(config.fetch().await).await;
+        let mut settings = config.fetch().await;
+        let output_enabled = power_on_output(&settings, reset_reason);
+        if output_enabled != settings.output_enabled {
+            let res = config
+                .update(Source::Boot, |settings| {
+                    settings.output_enabled = output_enabled
+                })
+                .await;
+            match res {
+                Ok(()) => settings = config.fetch().await,
+                Err(e) => {
+                    // Fail closed, instead of enabling the output as it was stored.
+                    log::warn!(
+                        "Failed to apply power-on policy, keeping output disabled: {:?}",
+                        e
+                    );
+                    settings.output_enabled = false;
+                }
+            }
+        }
+
+        system.persist(settings).await;
 
         spawner.must_spawn(monitor_task(bsp.nint_pin, system));
         spawner.must_spawn(config_task(config, system));
@@ -91,24 +162,34 @@ impl PowerExt {
     /// Persist configuration settings.
     async fn persist(&self, settings: Settings) {
         let vref = VRef::from_feedback(settings.vout_mv, FEEDBACK);
-
-        const CURRENT_SENSE_MILLIOHM: u32 = 20;
-        let limit_uv = settings.iout_ma.0 as u32 * CURRENT_SENSE_MILLIOHM;
-        let limit_value = (limit_uv / 500) as u8;
+        let limit_value = IoutLimit::from_current(settings.iout_ma, CURRENT_SENSE_MILLIOHM);
 
         let mut guard = self.inner.lock().await;
 
         guard.backoff_duration = Duration::from_millis(settings.backoff_ms as u64);
+        guard.output_enabled = settings.output_enabled;
 
         // TODO check with internal settings to prevent too many I2C transations.
         let ll = &mut guard.ll;
         ll.vref().write_async(|w| w.vref(vref)).await.unwrap();
         ll.iout_limit()
-            .modify_async(|w| w.setting(limit_value))
+            .modify_async(|w| w.setting(limit_value.into()))
             .await
             .unwrap();
 
-        log::info!("Persisted {:?} {:?}", settings.vout_mv, settings.iout_ma);
+        log::info!(
+            "Persisted {:?} {:?}, output {}",
+            settings.vout_mv,
+            settings.iout_ma,
+            if settings.output_enabled {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
+
+        // Have the monitor act on the output being enabled or disabled.
+        let _ = self.settings_notifier.try_send(());
     }
 
     pub async fn state(&self) -> State {
@@ -182,7 +263,22 @@ async fn monitor_task(mut nint_pin: bsp::PowerExtNIntPin, system: &'static Power
                 }
 
                 system.usbpd.set_pin(false).await;
-            } else if !enabled {
+            } else if enabled && !inner.output_enabled {
+                inner.state = State::Disabled;
+                log::info!("Disabling");
+
+                inner
+                    .ll
+                    .mode()
+                    .modify_async(|w| w.dischg(true).oe(false))
+                    .await
+                    .unwrap();
+
+                enabled = false;
+                stabilized_at = None;
+
+                system.usbpd.set_pin(false).await;
+            } else if !enabled && inner.output_enabled {
                 let activate = if let Some(until) = backoff_until {
                     until < Instant::now()
                 } else {
@@ -228,11 +324,11 @@ async fn monitor_task(mut nint_pin: bsp::PowerExtNIntPin, system: &'static Power
             earliest_deadline([Some(awaken_anyway_at), backoff_until, stabilized_at].into_iter())
                 .unwrap();
 
-        match embassy_futures::select::select(nint_pin.wait_for_falling_edge(), Timer::at(deadline))
-            .await
-        {
-            embassy_futures::select::Either::First(_) => {}
-            embassy_futures::select::Either::Second(_) => {}
-        }
+        embassy_futures::select::select3(
+            nint_pin.wait_for_falling_edge(),
+            Timer::at(deadline),
+            system.settings_notifier.receive(),
+        )
+        .await;
     }
 }
@@ -0,0 +1,233 @@
+//! Named settings, persisted such that they can be recalled at runtime.
+
+use embassy_executor::Spawner;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, pubsub::WaitResult,
+};
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+use static_cell::StaticCell;
+
+use crate::systems::{
+    config::{Config, Rejection, Settings, Source, UpdateError},
+    storage::{self, MaxSize, Storage, StorageEntry, StorageKey},
+};
+
+pub const MAX_PRESETS: usize = 8;
+pub const NAME_SIZE: usize = 16;
+
+pub type Name = String<NAME_SIZE>;
+
+/// Recalling a preset only applies its output levels, see [Settings::apply_to].
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Preset {
+    pub name: Name,
+    pub settings: Settings,
+}
+
+impl MaxSize for Preset {
+    const MAX_SIZE: usize = Name::MAX_SIZE + Settings::MAX_SIZE;
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Table {
+    presets: Vec<Preset, MAX_PRESETS>,
+    /// Name of the preset that is applied when booting.
+    boot_default: Option<Name>,
+}
+
+impl MaxSize for Table {
+    const MAX_SIZE: usize = Vec::<Preset, MAX_PRESETS>::MAX_SIZE + Option::<Name>::MAX_SIZE;
+}
+
+impl StorageEntry for Table {
+    const KEY: StorageKey = StorageKey::Presets;
+}
+
+impl Table {
+    fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+}
+
+/// Overview of the available presets, small enough to fit in a single message.
+#[derive(Debug, Serialize)]
+pub struct Listing {
+    pub names: Vec<Name, MAX_PRESETS>,
+    pub boot_default: Option<Name>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Error {
+    InvalidName,
+    UnknownPreset,
+    TableFull,
+    Rejected { rejection: Rejection },
+    Storage,
+}
+
+struct Inner {
+    table: Table,
+}
+
+pub struct Presets {
+    inner: Mutex<CriticalSectionRawMutex, Inner>,
+    config: &'static Config,
+    storage: &'static Storage,
+}
+
+impl Presets {
+    /// Apply the boot default preset, if any and still within the limits.
+    pub async fn init(
+        config: &'static Config,
+        storage: &'static Storage,
+        spawner: &Spawner,
+    ) -> &'static Self {
+        let table = match storage.fetch::<Table>().await {
+            Ok(table) => table.unwrap_or_default(),
+            Err(e) => {
+                // Do not overwrite, such that a later firmware might still make sense of it.
+                log::error!("Failed to fetch presets, starting without: {:?}", e);
+                Table::default()
+            }
+        };
+
+        if let Some(preset) = table.boot_default.as_ref().and_then(|name| table.get(name)) {
+            log::info!("Applying boot default preset \"{}\"", preset.name);
+            let name = &preset.name;
+            let preset = preset.settings;
+            let res = config
+                .update(Source::Boot, |settings| {
+                    *settings = preset.apply_to(*settings)
+                })
+                .await;
+            if let Err(e) = res {
+                log::warn!("Failed to apply boot default preset \"{}\": {:?}", name, e);
+            }
+        }
+
+        let system = Presets {
+            inner: Mutex::new(Inner { table }),
+            config,
+            storage,
+        };
+
+        static SYSTEM: StaticCell<Presets> = StaticCell::new();
+        let system = SYSTEM.init(system);
+
+        spawner.must_spawn(reset_task(system));
+
+        system
+    }
+
+    /// Save the current settings under `name`, replacing any preset with the same name.
+    pub async fn save(&self, name: &str) -> Result<(), Error> {
+        let name = Name::try_from(name).map_err(|_| Error::InvalidName)?;
+        if name.is_empty() {
+            return Err(Error::InvalidName);
+        }
+
+        let settings = self.config.fetch().await;
+
+        let mut guard = self.inner.lock().await;
+        let mut table = guard.table.clone();
+        match table.presets.iter_mut().find(|preset| preset.name == name) {
+            Some(preset) => preset.settings = settings,
+            None => table
+                .presets
+                .push(Preset { name, settings })
+                .map_err(|_| Error::TableFull)?,
+        }
+
+        self.persist(&mut guard, table).await
+    }
+
+    /// Apply the preset called `name`, provided it is still within the limits.
+    pub async fn recall(&self, name: &str) -> Result<(), Error> {
+        let preset = {
+            let guard = self.inner.lock().await;
+            guard.table.get(name).ok_or(Error::UnknownPreset)?.settings
+        };
+
+        // The whole preset is applied as a single update.
+        self.config
+            .update(Source::Preset, |settings| {
+                *settings = preset.apply_to(*settings)
+            })
+            .await
+            .map_err(|e| match e {
+                UpdateError::Rejected(rejection) => Error::Rejected { rejection },
+                UpdateError::Storage(_) => Error::Storage,
+            })?;
+
+        log::info!("Recalled preset \"{}\"", name);
+        Ok(())
+    }
+
+    /// Mark the preset called `name` to be applied at boot, or clear the boot default.
+    pub async fn set_boot_default(&self, name: Option<&str>) -> Result<(), Error> {
+        let mut guard = self.inner.lock().await;
+        let mut table = guard.table.clone();
+        table.boot_default = match name {
+            Some(name) => Some(
+                guard
+                    .table
+                    .get(name)
+                    .ok_or(Error::UnknownPreset)?
+                    .name
+                    .clone(),
+            ),
+            None => None,
+        };
+
+        self.persist(&mut guard, table).await
+    }
+
+    pub async fn list(&self) -> Listing {
+        let guard = self.inner.lock().await;
+        Listing {
+            names: guard
+                .table
+                .presets
+                .iter()
+                .map(|preset| preset.name.clone())
+                .collect(),
+            boot_default: guard.table.boot_default.clone(),
+        }
+    }
+
+    /// Forget all presets, without persisting.
+    async fn restore_defaults(&self) {
+        let mut guard = self.inner.lock().await;
+        guard.table = Table::default();
+
+        log::info!("Restored defaults");
+    }
+
+    /// Store the table, only adopting it when it has been persisted.
+    async fn persist(&self, inner: &mut Inner, table: Table) -> Result<(), Error> {
+        if inner.table == table {
+            return Ok(());
+        }
+
+        self.storage.store(table.clone()).await.map_err(|e| {
+            log::error!("Failed to store presets: {:?}", e);
+            Error::Storage
+        })?;
+        inner.table = table;
+
+        log::info!("Synced presets");
+        Ok(())
+    }
+}
+
+#[embassy_executor::task]
+async fn reset_task(system: &'static Presets) {
+    let mut subscriber = system.storage.subscriber();
+    loop {
+        if let WaitResult::Message(storage::Event::FactoryReset) = subscriber.next_message().await {
+            system.restore_defaults().await;
+        }
+    }
+}
@@ -2,15 +2,17 @@
 
 use embassy_executor::Spawner;
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex,
-    pubsub::PubSubBehavior,
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::Channel,
+    mutex::Mutex,
+    pubsub::{PubSubBehavior, WaitResult},
 };
 use embassy_time::{Duration, Instant, Timer};
 use serde::{Deserialize, Serialize};
 use static_cell::StaticCell;
 
 use crate::{
-    systems::storage::{Storage, StorageEntry, StorageKey},
+    systems::storage::{self, MaxSize, Storage, StorageEntry, StorageKey},
     util::{PubSub, Sub},
 };
 
@@ -26,6 +28,10 @@ pub struct Data {
     pub overcurrent_secs: u64,
 }
 
+impl MaxSize for Data {
+    const MAX_SIZE: usize = 2 * u64::MAX_SIZE;
+}
+
 impl StorageEntry for Data {
     const KEY: StorageKey = StorageKey::RecordData;
 }
@@ -44,7 +50,13 @@ pub struct Record {
 
 impl Record {
     pub async fn init(storage: &'static Storage, spawner: &Spawner) -> &'static Self {
-        let data = storage.fetch::<Data>().await.unwrap().unwrap_or_default();
+        let data = match storage.fetch::<Data>().await {
+            Ok(data) => data.unwrap_or_default(),
+            Err(e) => {
+                log::error!("Failed to fetch record, starting afresh: {:?}", e);
+                Data::default()
+            }
+        };
 
         let system = Record {
             inner: Mutex::new(Inner {
@@ -61,6 +73,7 @@ impl Record {
 
         spawner.must_spawn(sync_task(system));
         spawner.must_spawn(push_task(system));
+        spawner.must_spawn(reset_task(system));
 
         system
     }
@@ -81,6 +94,20 @@ impl Record {
         }
     }
 
+    /// Revert to an empty record, without persisting it.
+    async fn restore_defaults(&self) {
+        let mut guard = self.inner.lock().await;
+        guard.data = Data::default();
+        self.data_notifier.publish_immediate(guard.data.clone());
+
+        log::info!("Restored defaults");
+    }
+
+    pub async fn fetch(&self) -> Data {
+        let guard = self.inner.lock().await;
+        guard.data.clone()
+    }
+
     /// Publish the current record to all participants, immediately.
     pub async fn publish_immediate(&self) {
         let guard = self.inner.lock().await;
@@ -131,3 +158,13 @@ async fn push_task(system: &'static Record) {
         }
     }
 }
+
+#[embassy_executor::task]
+async fn reset_task(system: &'static Record) {
+    let mut subscriber = system.storage.subscriber();
+    loop {
+        if let WaitResult::Message(storage::Event::FactoryReset) = subscriber.next_message().await {
+            system.restore_defaults().await;
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Upper bounds of the size of values serialized with postcard.
+
+use heapless::{String, Vec};
+
+/// Upper bound of the size of a value serialized with postcard, such that entries are known to fit at compile time.
+///
+/// For structs this is the sum of the bounds of their fields.
+/// For enums it is a byte for the variant, plus the bound of the largest variant.
+pub trait MaxSize {
+    const MAX_SIZE: usize;
+}
+
+/// Size of a length as postcard encodes it, as a varint of 7 bits per byte.
+pub const fn varint_size(mut value: usize) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+macro_rules! impl_max_size {
+    ($($ty:ty => $size:expr),* $(,)?) => {
+        $(
+            impl MaxSize for $ty {
+                const MAX_SIZE: usize = $size;
+            }
+        )*
+    };
+}
+
+// Integers wider than a byte are encoded as varints.
+impl_max_size!(
+    () => 0,
+    bool => 1,
+    u8 => 1,
+    u16 => 3,
+    u32 => 5,
+    u64 => 10,
+);
+
+impl<T: MaxSize> MaxSize for Option<T> {
+    const MAX_SIZE: usize = 1 + T::MAX_SIZE;
+}
+
+impl<T: MaxSize, const N: usize> MaxSize for [T; N] {
+    const MAX_SIZE: usize = N * T::MAX_SIZE;
+}
+
+impl<const N: usize> MaxSize for String<N> {
+    const MAX_SIZE: usize = varint_size(N) + N;
+}
+
+impl<T: MaxSize, const N: usize> MaxSize for Vec<T, N> {
+    const MAX_SIZE: usize = varint_size(N) + N * T::MAX_SIZE;
+}
@@ -0,0 +1,829 @@
+//! Persistent storage on flash.
+
+use core::{cmp::Ordering, ops::Range};
+
+use derive_more::From;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex,
+    pubsub::PubSubBehavior,
+};
+use embassy_time::Duration;
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash};
+use heapless::HistoryBuffer;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use sequential_storage::cache::{KeyPointerCache, PagePointerCache};
+use serde::{Deserialize, Serialize};
+
+#[cfg(not(test))]
+use embassy_embedded_hal::adapter::BlockingAsync;
+#[cfg(not(test))]
+use embassy_executor::Spawner;
+#[cfg(not(test))]
+use embassy_time::Timer;
+#[cfg(not(test))]
+use esp_partition_table::{DataPartitionType, PartitionEntry, PartitionTable, PartitionType};
+#[cfg(not(test))]
+use esp_storage::FlashStorage;
+#[cfg(not(test))]
+use static_cell::StaticCell;
+
+use crate::util::{PubSub, Sub};
+
+pub use sequential_storage::map::SerializationError;
+
+mod max_size;
+// Only used to exercise storage without real flash.
+#[cfg(test)]
+pub mod ram;
+#[cfg(test)]
+mod tests;
+mod wear;
+
+pub use max_size::MaxSize;
+use wear::Metered;
+pub use wear::{Health, Wear, WEAR_WARNING_ERASES};
+
+/// Largest entry that can be stored, including its key and version header, as read from flash in whole words.
+///
+/// When scanning or reclaiming pages every item is read into the same buffer, hence it is sized for the largest entry,
+/// being a certificate. Entries are checked to fit at compile time, see [StorageEntry::BUFFER_SIZE].
+/// Entries also have to fit within a single page of flash, including the overhead of the item headers.
+pub const MAX_ENTRY_SIZE: usize = 1540;
+
+/// Items are read and written in whole words, which is a multiple of those of the flash.
+const WORD_SIZE: usize = 4;
+
+/// Maximum number of pages we use from the partition, which the cache is sized for.
+const MAX_PAGES: usize = 8;
+
+/// Every key might be stored both versioned, and from before the introduction of versioning.
+const MAX_KEYS: usize = 2 * StorageKey::COUNT;
+
+/// Remembers page states and the location of the latest item for each key, preventing a scan of the entire partition.
+type Cache = KeyPointerCache<MAX_PAGES, ItemKey, MAX_KEYS>;
+
+/// Maximum number of pages of the journal partition we use.
+const JOURNAL_MAX_PAGES: usize = 8;
+
+/// Name of the optional data partition holding the journal.
+const JOURNAL_PARTITION: &str = "journal";
+
+type JournalCache = PagePointerCache<JOURNAL_MAX_PAGES>;
+
+// Persist the wear counters only every once in a while, as persisting them causes wear itself.
+const WEAR_SYNC_PERIOD: Duration = Duration::from_secs(600);
+const HEALTH_PUSH_PERIOD: Duration = Duration::from_secs(60);
+
+type NotifyChannel = Channel<CriticalSectionRawMutex, (), 1>;
+
+/// Flash the storage is kept in, being the on-chip flash of the ESP.
+#[cfg(not(test))]
+pub type Flash = BlockingAsync<FlashStorage>;
+
+/// Flash simulated in RAM, when testing on the host.
+#[cfg(test)]
+pub type Flash = ram::RamFlash<{ tests::FLASH_SIZE }>;
+
+pub struct Storage<F: NorFlash = Flash> {
+    inner: Mutex<CriticalSectionRawMutex, Inner<F>>,
+    notifier: PubSub<Event>,
+    sync_notifier: NotifyChannel,
+    health_notifier: PubSub<Health>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Event {
+    /// All entries have been erased, users should revert to their defaults.
+    FactoryReset,
+    /// A page has been erased more than [WEAR_WARNING_ERASES] times.
+    WearWarning,
+}
+
+struct Inner<F: NorFlash> {
+    flash: Metered<F>,
+    range: Range<u32>,
+    cache: Cache,
+    journal: Option<Journal>,
+    /// Scratch space for items as written to and read from flash, kept with the storage due to its size.
+    buffer: [u8; MAX_ENTRY_SIZE],
+    sync_scheduled: bool,
+    wear_warned: bool,
+}
+
+/// Queue of entries in a separate range of flash, discarding the oldest entries when full.
+struct Journal {
+    range: Range<u32>,
+    cache: JournalCache,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Marker;
+
+/// Records where the journal was formatted, such that it is erased when the partition is new or moved.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct JournalMarker {
+    start: u32,
+    end: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum StorageKey {
+    Marker = 0x01,
+    RecordData = 0x02,
+    ConfigSettings = 0x03,
+    Presets = 0x04,
+    ConfigChange = 0x05,
+    JournalMarker = 0x06,
+    Wear = 0x07,
+    WifiCredentials = 0x08,
+    BrokerSettings = 0x09,
+    Identity = 0x0A,
+    CaCertificate = 0x0B,
+    ClientCertificate = 0x0C,
+    ClientKey = 0x0D,
+}
+
+impl StorageKey {
+    /// Every key, such that the number of keys follows from it.
+    const ALL: [StorageKey; 13] = [
+        StorageKey::Marker,
+        StorageKey::RecordData,
+        StorageKey::ConfigSettings,
+        StorageKey::Presets,
+        StorageKey::ConfigChange,
+        StorageKey::JournalMarker,
+        StorageKey::Wear,
+        StorageKey::WifiCredentials,
+        StorageKey::BrokerSettings,
+        StorageKey::Identity,
+        StorageKey::CaCertificate,
+        StorageKey::ClientCertificate,
+        StorageKey::ClientKey,
+    ];
+
+    const COUNT: usize = Self::ALL.len();
+}
+
+/// A value that can be persisted in storage.
+///
+/// Every value is stored with the schema version it was serialized with.
+/// When changing the layout of an entry, keep the previous layout around as a separate type,
+/// bump [StorageEntry::VERSION] and implement [StorageEntry::migrate] using [migrate_from].
+/// Values of older versions are then upgraded in place when they are fetched.
+pub trait StorageEntry: Serialize + for<'a> Deserialize<'a> + MaxSize {
+    const KEY: StorageKey;
+
+    /// Schema version of the current layout.
+    const VERSION: u8 = 0;
+
+    /// Buffer required to store or fetch this entry: its key, version header and value, rounded up to whole words.
+    const BUFFER_SIZE: usize = (2 + Self::MAX_SIZE).next_multiple_of(WORD_SIZE);
+
+    /// Upgrade a value serialized with an older schema `version` to the current layout.
+    fn migrate(version: u8, _buffer: &[u8]) -> Result<Self, SerializationError> {
+        log::error!("No migration for {:?} from version {}", Self::KEY, version);
+        Err(SerializationError::InvalidFormat)
+    }
+}
+
+impl MaxSize for Marker {
+    const MAX_SIZE: usize = 0;
+}
+
+impl StorageEntry for Marker {
+    const KEY: StorageKey = StorageKey::Marker;
+}
+
+impl MaxSize for JournalMarker {
+    const MAX_SIZE: usize = 2 * u32::MAX_SIZE;
+}
+
+impl StorageEntry for JournalMarker {
+    const KEY: StorageKey = StorageKey::JournalMarker;
+}
+
+/// Migrate a value by way of `P`, the layout preceding the current layout of `T`.
+///
+/// As `P` in turn migrates from its own predecessor, this forms a chain up to the oldest version.
+pub fn migrate_from<P: StorageEntry, T: From<P>>(
+    version: u8,
+    buffer: &[u8],
+) -> Result<T, SerializationError> {
+    decode::<P>(version, buffer).map(T::from)
+}
+
+/// Check at compile time whether an entry fits the buffer.
+const fn assert_fits<T: StorageEntry>() {
+    assert!(
+        T::BUFFER_SIZE <= MAX_ENTRY_SIZE,
+        "Entry does not fit MAX_ENTRY_SIZE"
+    );
+}
+
+/// Serialize a value, prefixed with its schema version.
+fn encode<T: StorageEntry>(value: &T, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+    let (header, body) = buffer
+        .split_first_mut()
+        .ok_or(SerializationError::BufferTooSmall)?;
+    *header = T::VERSION;
+
+    let body = postcard::to_slice(value, body).map_err(|_| SerializationError::BufferTooSmall)?;
+    Ok(1 + body.len())
+}
+
+/// Deserialize a value that was serialized with schema `version`, migrating it if required.
+fn decode<T: StorageEntry>(version: u8, buffer: &[u8]) -> Result<T, SerializationError> {
+    match version.cmp(&T::VERSION) {
+        Ordering::Equal => {
+            postcard::from_bytes(buffer).map_err(|_| SerializationError::InvalidData)
+        }
+        Ordering::Less => T::migrate(version, buffer),
+        Ordering::Greater => {
+            // Written by a newer firmware, we can not know what it looks like.
+            log::error!(
+                "{:?} has version {}, newer than supported version {}",
+                T::KEY,
+                version,
+                T::VERSION
+            );
+            Err(SerializationError::InvalidFormat)
+        }
+    }
+}
+
+/// Value that is serialized with its schema version, directly into the buffer of the item.
+struct Encoded<'v, T>(&'v T);
+
+impl<'a, T: StorageEntry> sequential_storage::map::Value<'a> for Encoded<'_, T> {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        encode(self.0, buffer)
+    }
+
+    fn deserialize_from(_buffer: &'a [u8]) -> Result<Self, SerializationError> {
+        // Only used for storing, values are fetched as raw bytes.
+        Err(SerializationError::InvalidFormat)
+    }
+}
+
+/// Key as stored in flash.
+///
+/// Entries written before the introduction of schema versions have no version header.
+/// These are stored under the bare key, whereas versioned entries have the [VERSIONED] bit set.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct ItemKey {
+    key: StorageKey,
+    versioned: bool,
+}
+
+const VERSIONED: u8 = 0x80;
+
+impl ItemKey {
+    fn versioned(key: StorageKey) -> Self {
+        Self {
+            key,
+            versioned: true,
+        }
+    }
+
+    fn legacy(key: StorageKey) -> Self {
+        Self {
+            key,
+            versioned: false,
+        }
+    }
+}
+
+impl sequential_storage::map::Key for ItemKey {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.is_empty() {
+            return Err(SerializationError::BufferTooSmall);
+        }
+
+        let mut determinator = u8::from(self.key);
+        if self.versioned {
+            determinator |= VERSIONED;
+        }
+        buffer[0] = determinator;
+        Ok(1)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
+        if buffer.is_empty() {
+            return Err(SerializationError::InvalidFormat);
+        }
+
+        let determinator = buffer[0];
+        let key = StorageKey::try_from_primitive(determinator & !VERSIONED)
+            .map_err(|_| SerializationError::InvalidData)?;
+        Ok((
+            ItemKey {
+                key,
+                versioned: determinator & VERSIONED != 0,
+            },
+            1,
+        ))
+    }
+}
+
+#[derive(From, Debug)]
+#[allow(unused)]
+pub enum Error<E = <Flash as ErrorType>::Error> {
+    Serialization(SerializationError),
+    Flash(sequential_storage::Error<E>),
+    /// No journal partition is available.
+    NoJournal,
+    /// The entry does not fit within [MAX_ENTRY_SIZE] or a single page of flash.
+    TooLarge(StorageKey),
+}
+
+impl<E> Error<E> {
+    /// Attribute running out of space to the entry being stored.
+    fn too_large(self, key: StorageKey) -> Self {
+        match self {
+            Error::Serialization(SerializationError::BufferTooSmall)
+            | Error::Flash(sequential_storage::Error::ItemTooBig)
+            | Error::Flash(sequential_storage::Error::BufferTooSmall(_)) => Error::TooLarge(key),
+            e => e,
+        }
+    }
+}
+
+#[cfg(not(test))]
+fn range(p: &PartitionEntry) -> Range<u32> {
+    p.offset..(p.offset + p.size as u32)
+}
+
+/// Limit a range to the number of pages our caches are sized for.
+fn clamp<F: NorFlash>(mut range: Range<u32>, max_pages: usize) -> Range<u32> {
+    let max_size = (max_pages * F::ERASE_SIZE) as u32;
+    if range.len() > max_size as usize {
+        log::warn!(
+            "Only using the first {:#x} bytes of the range at {:#x}",
+            max_size,
+            range.start
+        );
+        range.end = range.start + max_size;
+    }
+    range
+}
+
+impl<F: NorFlash> Inner<F> {
+    async fn ensure_initialized(&mut self) -> Result<(), Error<F::Error>> {
+        match self.fetch::<Marker>().await {
+            Ok(Some(Marker)) => {
+                log::debug!("Marker detected");
+                return self.ensure_journal_initialized().await;
+            }
+            Ok(None) => {
+                log::warn!("No marker detected");
+            }
+            Err(e) => {
+                log::error!("Failed to ensure storage: {:?}", e);
+            }
+        }
+
+        self.initialize().await
+    }
+
+    /// Erase everything, and mark the storage as ours.
+    async fn initialize(&mut self) -> Result<(), Error<F::Error>> {
+        log::debug!("Erasing storage");
+        self.erase_all().await?;
+
+        log::debug!("Storing marker");
+        self.store(&Marker).await?;
+
+        // Wear is a property of the flash, not of its contents.
+        self.store(&self.flash.wear.clone()).await?;
+        self.flash.dirty = false;
+
+        log::info!("Storage initialized");
+
+        self.initialize_journal().await
+    }
+
+    async fn ensure_journal_initialized(&mut self) -> Result<(), Error<F::Error>> {
+        let Some(journal) = self.journal.as_ref() else {
+            return Ok(());
+        };
+
+        let expected = JournalMarker {
+            start: journal.range.start,
+            end: journal.range.end,
+        };
+        match self.fetch::<JournalMarker>().await {
+            Ok(Some(marker)) if marker == expected => {
+                log::debug!("Journal marker detected");
+                Ok(())
+            }
+            _ => {
+                log::warn!("Journal not initialized");
+                self.initialize_journal().await
+            }
+        }
+    }
+
+    /// Erase the journal, if any, and mark it as ours.
+    async fn initialize_journal(&mut self) -> Result<(), Error<F::Error>> {
+        let Some(journal) = self.journal.as_mut() else {
+            return Ok(());
+        };
+
+        log::debug!("Erasing journal");
+        journal.cache = JournalCache::new();
+        sequential_storage::erase_all(&mut self.flash, journal.range.clone()).await?;
+
+        let marker = JournalMarker {
+            start: journal.range.start,
+            end: journal.range.end,
+        };
+        self.store(&marker).await?;
+
+        log::info!("Journal initialized");
+
+        Ok(())
+    }
+
+    async fn erase_all(&mut self) -> Result<(), Error<F::Error>> {
+        // Whatever the outcome, the cache no longer reflects what is in flash.
+        self.cache = Cache::new();
+        sequential_storage::erase_all(&mut self.flash, self.range.clone()).await?;
+        Ok(())
+    }
+
+    async fn fetch_raw(&mut self, key: ItemKey) -> Result<Option<&[u8]>, Error<F::Error>> {
+        let res: Result<Option<&[u8]>, sequential_storage::Error<F::Error>> =
+            sequential_storage::map::fetch_item(
+                &mut self.flash,
+                self.range.clone(),
+                &mut self.cache,
+                &mut self.buffer,
+                key,
+            )
+            .await;
+        res.map_err(|e| Error::from(e).too_large(key.key))
+    }
+
+    pub async fn fetch<T: StorageEntry>(&mut self) -> Result<Option<T>, Error<F::Error>> {
+        const { assert_fits::<T>() };
+        let (version, value) = match self.fetch_raw(ItemKey::versioned(T::KEY)).await? {
+            Some(raw) => {
+                let (&version, body) =
+                    raw.split_first().ok_or(SerializationError::InvalidFormat)?;
+                (version, decode::<T>(version, body)?)
+            }
+            None => match self.fetch_raw(ItemKey::legacy(T::KEY)).await? {
+                // Stored before the introduction of versioning, hence implicitly version 0.
+                Some(raw) => (0, decode::<T>(0, raw)?),
+                None => return Ok(None),
+            },
+        };
+
+        if version != T::VERSION {
+            // Upgrade in place, such that we only need to migrate once.
+            self.store(&value).await?;
+            log::info!(
+                "Migrated {:?} from version {} to {}",
+                T::KEY,
+                version,
+                T::VERSION
+            );
+        }
+
+        Ok(Some(value))
+    }
+
+    pub async fn store<T: StorageEntry>(&mut self, value: &T) -> Result<(), Error<F::Error>> {
+        const { assert_fits::<T>() };
+        // Erasing while storing means a page had to be reclaimed.
+        let erase_count = self.flash.wear.total_erase_count();
+
+        let res = sequential_storage::map::store_item(
+            &mut self.flash,
+            self.range.clone(),
+            &mut self.cache,
+            &mut self.buffer,
+            ItemKey::versioned(T::KEY),
+            &Encoded(value),
+        )
+        .await;
+
+        if self.flash.wear.total_erase_count() != erase_count {
+            self.flash.wear.gc_count += 1;
+        }
+
+        res.map_err(|e| Error::from(e).too_large(T::KEY))
+    }
+
+    async fn health(&mut self) -> Result<Health, Error<F::Error>> {
+        let free_bytes = self
+            .flash
+            .free_bytes()
+            .await
+            .map_err(|e| Error::Flash(sequential_storage::Error::Storage { value: e }))?;
+
+        Ok(Health {
+            wear: self.flash.wear.clone(),
+            free_bytes,
+            wear_warning: self.wear_warned,
+        })
+    }
+
+    async fn append<T: StorageEntry>(&mut self, value: &T) -> Result<(), Error<F::Error>> {
+        const { assert_fits::<T>() };
+        let journal = self.journal.as_mut().ok_or(Error::NoJournal)?;
+
+        // Entries are tagged with their key, such that different entries can share the journal.
+        let buffer = &mut self.buffer;
+        buffer[0] = T::KEY.into();
+        let len =
+            1 + encode(value, &mut buffer[1..]).map_err(|e| Error::from(e).too_large(T::KEY))?;
+
+        sequential_storage::queue::push(
+            &mut self.flash,
+            journal.range.clone(),
+            &mut journal.cache,
+            &buffer[..len],
+            true,
+        )
+        .await
+        .map_err(|e| Error::from(e).too_large(T::KEY))
+    }
+
+    async fn latest<T: StorageEntry, const N: usize>(
+        &mut self,
+    ) -> Result<HistoryBuffer<T, N>, Error<F::Error>> {
+        const { assert_fits::<T>() };
+        let journal = self.journal.as_mut().ok_or(Error::NoJournal)?;
+
+        let mut entries = HistoryBuffer::new();
+        let buffer = &mut self.buffer;
+        let mut iter = sequential_storage::queue::iter(
+            &mut self.flash,
+            journal.range.clone(),
+            &mut journal.cache,
+        )
+        .await?;
+
+        // From oldest to newest, such that only the latest remain.
+        while let Some(entry) = iter.next(buffer).await? {
+            let Some((&key, raw)) = entry.split_first() else {
+                continue;
+            };
+            if key != u8::from(T::KEY) {
+                continue;
+            }
+            let Some((&version, body)) = raw.split_first() else {
+                continue;
+            };
+
+            match decode::<T>(version, body) {
+                Ok(value) => entries.write(value),
+                Err(e) => log::warn!("Skipping undecodable {:?}: {:?}", T::KEY, e),
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(not(test))]
+impl Storage {
+    pub async fn init(spawner: &Spawner) -> &'static Self {
+        let partition_table = PartitionTable::default();
+        let mut storage = FlashStorage::new();
+
+        let mut found_nvs = None;
+        let mut found_journal = None;
+        log::info!("Scanning partition table");
+        for entry in partition_table.iter_storage(&mut storage, true) {
+            let entry = entry.unwrap();
+            log::debug!("{:?}", entry);
+
+            if entry.type_ == PartitionType::Data(DataPartitionType::Nvs) {
+                found_nvs = Some(entry);
+            } else if entry.type_ == PartitionType::Data(DataPartitionType::Undefined)
+                && entry.name() == JOURNAL_PARTITION
+            {
+                found_journal = Some(entry);
+            }
+        }
+
+        let found_nvs = found_nvs.expect("No NVS partition found");
+
+        log::info!(
+            "Using partition \"{}\", offset {:#x}, size {:#x} bytes",
+            found_nvs.name(),
+            found_nvs.offset,
+            found_nvs.size
+        );
+
+        let journal_range = match found_journal {
+            Some(found_journal) => {
+                log::info!(
+                    "Using partition \"{}\" for the journal, offset {:#x}, size {:#x} bytes",
+                    found_journal.name(),
+                    found_journal.offset,
+                    found_journal.size
+                );
+                Some(range(&found_journal))
+            }
+            None => {
+                log::warn!("No journal partition found, journal disabled");
+                None
+            }
+        };
+
+        let system = Self::new(
+            BlockingAsync::new(storage),
+            range(&found_nvs),
+            journal_range,
+        )
+        .await
+        .unwrap();
+
+        static SYSTEM: StaticCell<Storage> = StaticCell::new();
+        let system = SYSTEM.init(system);
+
+        spawner.must_spawn(sync_task(system));
+        spawner.must_spawn(push_task(system));
+
+        system
+    }
+}
+
+impl<F: NorFlash> Storage<F> {
+    /// Use the given range of flash as storage, erasing it if it does not contain our storage yet.
+    ///
+    /// The journal, if any, is kept in a separate range.
+    pub async fn new(
+        flash: F,
+        range: Range<u32>,
+        journal_range: Option<Range<u32>>,
+    ) -> Result<Self, Error<F::Error>> {
+        const {
+            assert!(
+                WORD_SIZE.is_multiple_of(F::READ_SIZE) && WORD_SIZE.is_multiple_of(F::WRITE_SIZE)
+            )
+        };
+
+        let range = clamp::<F>(range, MAX_PAGES);
+        let mut inner = Inner {
+            flash: Metered::new(flash, range.clone()),
+            range,
+            cache: Cache::new(),
+            journal: journal_range.map(|range| Journal {
+                range: clamp::<F>(range, JOURNAL_MAX_PAGES),
+                cache: JournalCache::new(),
+            }),
+            buffer: [0; MAX_ENTRY_SIZE],
+            sync_scheduled: false,
+            wear_warned: false,
+        };
+
+        // Before initializing, such that the wear is retained when the storage is erased.
+        match inner.fetch::<Wear>().await {
+            Ok(wear) => inner.flash.wear = wear.unwrap_or_default(),
+            Err(e) => log::warn!("Failed to fetch wear, starting afresh: {:?}", e),
+        }
+
+        inner.ensure_initialized().await?;
+
+        let system = Self {
+            inner: Mutex::new(inner),
+            notifier: PubSub::new(),
+            sync_notifier: NotifyChannel::new(),
+            health_notifier: PubSub::new(),
+        };
+
+        {
+            // Warn about wear right away.
+            let mut guard = system.inner.lock().await;
+            system.account(&mut guard).await;
+        }
+
+        Ok(system)
+    }
+
+    pub async fn store<T: StorageEntry>(&self, value: T) -> Result<(), Error<F::Error>> {
+        let mut guard = self.inner.lock().await;
+        let res = guard.store(&value).await;
+        self.account(&mut guard).await;
+        res
+    }
+
+    pub async fn fetch<T: StorageEntry>(&self) -> Result<Option<T>, Error<F::Error>> {
+        let mut guard = self.inner.lock().await;
+        guard.fetch().await
+    }
+
+    /// Append an entry to the journal, discarding the oldest entries if it is full.
+    pub async fn append<T: StorageEntry>(&self, value: &T) -> Result<(), Error<F::Error>> {
+        let mut guard = self.inner.lock().await;
+        let res = guard.append(value).await;
+        self.account(&mut guard).await;
+        res
+    }
+
+    /// Fetch the latest `N` entries of type `T` from the journal, from oldest to newest.
+    pub async fn latest<T: StorageEntry, const N: usize>(
+        &self,
+    ) -> Result<HistoryBuffer<T, N>, Error<F::Error>> {
+        let mut guard = self.inner.lock().await;
+        guard.latest().await
+    }
+
+    /// Erase all entries, and notify all users such that they revert to their defaults.
+    pub async fn factory_reset(&self) -> Result<(), Error<F::Error>> {
+        {
+            let mut guard = self.inner.lock().await;
+            let res = guard.initialize().await;
+            self.account(&mut guard).await;
+            res?;
+        }
+
+        log::warn!("Factory reset");
+        self.notifier.publish_immediate(Event::FactoryReset);
+        Ok(())
+    }
+
+    pub fn subscriber(&'static self) -> Sub<Event> {
+        self.notifier.subscriber().unwrap()
+    }
+
+    /// Schedule persisting the wear counters if they changed, and warn when the flash is getting worn.
+    async fn account(&self, inner: &mut Inner<F>) {
+        if inner.flash.dirty && !inner.sync_scheduled {
+            inner.sync_scheduled = true;
+            let _ = self.sync_notifier.try_send(());
+        }
+
+        let max_erase_count = inner.flash.wear.max_erase_count();
+        if !inner.wear_warned && max_erase_count >= WEAR_WARNING_ERASES {
+            inner.wear_warned = true;
+            log::warn!(
+                "Flash is wearing out, erased a page {} times",
+                max_erase_count
+            );
+
+            self.notifier.publish_immediate(Event::WearWarning);
+            match inner.health().await {
+                Ok(health) => self.health_notifier.publish_immediate(health),
+                Err(e) => log::error!("Failed to determine flash health: {:?}", e),
+            }
+        }
+    }
+
+    /// Persist the wear counters.
+    pub async fn sync_wear(&self) -> Result<(), Error<F::Error>> {
+        let mut guard = self.inner.lock().await;
+        guard.sync_scheduled = false;
+        guard.flash.dirty = false;
+
+        let wear = guard.flash.wear.clone();
+        let res = guard.store(&wear).await;
+        self.account(&mut guard).await;
+        res
+    }
+
+    pub async fn health(&self) -> Result<Health, Error<F::Error>> {
+        let mut guard = self.inner.lock().await;
+        guard.health().await
+    }
+
+    /// Publish the flash health to all participants, immediately.
+    pub async fn publish_health(&self) {
+        match self.health().await {
+            Ok(health) => self.health_notifier.publish_immediate(health),
+            Err(e) => log::error!("Failed to determine flash health: {:?}", e),
+        }
+    }
+
+    pub fn health_subscriber(&'static self) -> Sub<Health> {
+        self.health_notifier.subscriber().unwrap()
+    }
+}
+
+#[cfg(not(test))]
+#[embassy_executor::task]
+async fn sync_task(system: &'static Storage) {
+    loop {
+        system.sync_notifier.receive().await;
+        Timer::after(WEAR_SYNC_PERIOD).await;
+
+        match system.sync_wear().await {
+            Ok(()) => log::info!("Synced wear"),
+            Err(e) => log::error!("Failed to sync wear: {:?}", e),
+        }
+    }
+}
+
+#[cfg(not(test))]
+#[embassy_executor::task]
+async fn push_task(system: &'static Storage) {
+    loop {
+        Timer::after(HEALTH_PUSH_PERIOD).await;
+        system.publish_health().await;
+    }
+}
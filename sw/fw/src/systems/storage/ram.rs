@@ -0,0 +1,201 @@
+//! Flash simulator in RAM, to exercise storage without touching real flash.
+//!
+//! Behaves like the ESP NOR flash: writes can only clear bits, and erasing sets whole pages to `0xFF`.
+//! Faults can be injected to simulate what real flash might do to us.
+
+use embedded_storage_async::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+const WORD_SIZE: usize = 4;
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamFlashError {
+    OutOfBounds,
+    NotAligned,
+    /// The operation was interrupted by a simulated power loss.
+    PowerLoss,
+    /// The page could not be erased.
+    EraseFailure,
+}
+
+impl NorFlashError for RamFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            RamFlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            RamFlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            RamFlashError::PowerLoss | RamFlashError::EraseFailure => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// NOR flash of `SIZE` bytes, which must be a multiple of the page size.
+pub struct RamFlash<const SIZE: usize> {
+    data: [u8; SIZE],
+    /// Number of writes and erases that will still succeed before the power is lost.
+    power_loss_after: Option<usize>,
+    powered: bool,
+    failing_page: Option<usize>,
+    /// Number of operations performed, to assess the cost of storage operations.
+    pub reads: usize,
+    pub writes: usize,
+    pub erases: usize,
+}
+
+impl<const SIZE: usize> RamFlash<SIZE> {
+    /// Create a completely erased flash.
+    pub const fn new() -> Self {
+        assert!(SIZE.is_multiple_of(PAGE_SIZE));
+
+        Self {
+            data: [0xFF; SIZE],
+            power_loss_after: None,
+            powered: true,
+            failing_page: None,
+            reads: 0,
+            writes: 0,
+            erases: 0,
+        }
+    }
+
+    /// Lose power halfway during the write or erase after `count` more of those operations.
+    ///
+    /// The interrupted operation is only partially applied, and all operations fail until [RamFlash::power_cycle].
+    pub fn inject_power_loss(&mut self, count: usize) {
+        self.power_loss_after = Some(count);
+    }
+
+    /// Restore power after a simulated power loss.
+    pub fn power_cycle(&mut self) {
+        self.power_loss_after = None;
+        self.powered = true;
+    }
+
+    /// Fail every erase of the page at `index`, leaving its contents untouched.
+    pub fn inject_erase_failure(&mut self, index: Option<usize>) {
+        self.failing_page = index;
+    }
+
+    /// Flip a single bit, simulating data rot.
+    pub fn flip_bit(&mut self, offset: u32, bit: u8) {
+        self.data[offset as usize] ^= 1 << bit;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn check(&self, offset: u32, len: usize, align: usize) -> Result<(), RamFlashError> {
+        if !self.powered {
+            return Err(RamFlashError::PowerLoss);
+        }
+
+        let offset = offset as usize;
+        if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(RamFlashError::NotAligned);
+        }
+        if offset + len > SIZE {
+            return Err(RamFlashError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// Account for a mutating operation, returning whether the power is lost during it.
+    fn lose_power(&mut self) -> bool {
+        match self.power_loss_after.as_mut() {
+            Some(0) => {
+                self.power_loss_after = None;
+                self.powered = false;
+                true
+            }
+            Some(count) => {
+                *count -= 1;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl<const SIZE: usize> Default for RamFlash<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> ErrorType for RamFlash<SIZE> {
+    type Error = RamFlashError;
+}
+
+impl<const SIZE: usize> ReadNorFlash for RamFlash<SIZE> {
+    const READ_SIZE: usize = WORD_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        self.reads += 1;
+
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize> NorFlash for RamFlash<SIZE> {
+    const WRITE_SIZE: usize = WORD_SIZE;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if to < from {
+            return Err(RamFlashError::OutOfBounds);
+        }
+        self.check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+
+        for page in (from as usize..to as usize).step_by(Self::ERASE_SIZE) {
+            if self.failing_page == Some(page / Self::ERASE_SIZE) {
+                return Err(RamFlashError::EraseFailure);
+            }
+
+            self.erases += 1;
+
+            if self.lose_power() {
+                // Erasing was underway, leaving a partially erased page.
+                self.data[page..page + Self::ERASE_SIZE / 2].fill(0xFF);
+                return Err(RamFlashError::PowerLoss);
+            }
+
+            self.data[page..page + Self::ERASE_SIZE].fill(0xFF);
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        self.writes += 1;
+
+        // Only the first half of the bytes makes it to flash when losing power.
+        let len = if self.lose_power() {
+            bytes.len() / 2
+        } else {
+            bytes.len()
+        };
+
+        let offset = offset as usize;
+        for (target, byte) in self.data[offset..offset + len].iter_mut().zip(bytes) {
+            // NOR flash can only clear bits.
+            *target &= byte;
+        }
+
+        if len < bytes.len() {
+            return Err(RamFlashError::PowerLoss);
+        }
+        Ok(())
+    }
+}
+
+// Writes only clear bits, hence writing the same word multiple times is fine.
+impl<const SIZE: usize> MultiwriteNorFlash for RamFlash<SIZE> {}
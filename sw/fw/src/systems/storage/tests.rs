@@ -0,0 +1,479 @@
+//! Storage on top of simulated flash, including the faults real flash might exhibit.
+
+use core::ops::Range;
+
+use embassy_futures::block_on;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    migrate_from,
+    ram::{RamFlash, RamFlashError},
+    Error, Event, ItemKey, MaxSize, SerializationError, Storage, StorageEntry, StorageKey,
+    VERSIONED,
+};
+
+const PAGE_SIZE: usize = 4096;
+pub const FLASH_SIZE: usize = 16 * PAGE_SIZE;
+
+const RANGE: Range<u32> = 0..(8 * PAGE_SIZE) as u32;
+const JOURNAL_RANGE: Range<u32> = RANGE.end..RANGE.end + (4 * PAGE_SIZE) as u32;
+
+type Flash = RamFlash<FLASH_SIZE>;
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+struct Counter {
+    value: u32,
+    label: bool,
+}
+
+impl MaxSize for Counter {
+    const MAX_SIZE: usize = u32::MAX_SIZE + bool::MAX_SIZE;
+}
+
+impl StorageEntry for Counter {
+    const KEY: StorageKey = StorageKey::RecordData;
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, buffer: &[u8]) -> Result<Self, SerializationError> {
+        migrate_from::<CounterV0, _>(version, buffer)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CounterV0 {
+    value: u16,
+}
+
+impl MaxSize for CounterV0 {
+    const MAX_SIZE: usize = u16::MAX_SIZE;
+}
+
+impl StorageEntry for CounterV0 {
+    const KEY: StorageKey = StorageKey::RecordData;
+}
+
+impl From<CounterV0> for Counter {
+    fn from(value: CounterV0) -> Self {
+        Self {
+            value: value.value.into(),
+            label: false,
+        }
+    }
+}
+
+/// Same key as [Counter], but written by a newer firmware.
+#[derive(Serialize, Deserialize)]
+struct CounterV2 {
+    value: u32,
+}
+
+impl MaxSize for CounterV2 {
+    const MAX_SIZE: usize = u32::MAX_SIZE;
+}
+
+impl StorageEntry for CounterV2 {
+    const KEY: StorageKey = StorageKey::RecordData;
+    const VERSION: u8 = 2;
+}
+
+fn counter(value: u32) -> Counter {
+    Counter { value, label: true }
+}
+
+fn boot(flash: Flash) -> Storage<Flash> {
+    block_on(Storage::new(flash, RANGE, Some(JOURNAL_RANGE))).unwrap()
+}
+
+fn shutdown(storage: Storage<Flash>) -> Flash {
+    storage.inner.into_inner().flash.flash
+}
+
+#[test]
+fn store_survives_reboot() {
+    let storage = boot(Flash::new());
+    block_on(storage.store(counter(1))).unwrap();
+    block_on(storage.store(counter(2))).unwrap();
+
+    let storage = boot(shutdown(storage));
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(counter(2))
+    );
+}
+
+#[test]
+fn interrupted_store_keeps_either_value() {
+    let mut completed = false;
+    for count in 0.. {
+        let storage = boot(Flash::new());
+        block_on(storage.store(counter(1))).unwrap();
+
+        let mut flash = shutdown(storage);
+        flash.inject_power_loss(count);
+        let storage = boot(flash);
+        let res = block_on(storage.store(counter(2)));
+        if res.is_ok() {
+            completed = true;
+        }
+
+        let mut flash = shutdown(storage);
+        flash.power_cycle();
+        let storage = boot(flash);
+        let fetched = block_on(storage.fetch::<Counter>()).unwrap();
+        if completed {
+            assert_eq!(fetched, Some(counter(2)));
+            break;
+        }
+        assert!(
+            fetched == Some(counter(1)) || fetched == Some(counter(2)),
+            "Power loss after {} operations yielded {:?}",
+            count,
+            fetched
+        );
+
+        // Storage is usable again after the power loss.
+        block_on(storage.store(counter(3))).unwrap();
+        assert_eq!(
+            block_on(storage.fetch::<Counter>()).unwrap(),
+            Some(counter(3))
+        );
+    }
+}
+
+#[test]
+fn interrupted_factory_reset_reinitializes() {
+    for count in 0..(RANGE.len() / PAGE_SIZE) {
+        let storage = boot(Flash::new());
+        block_on(storage.store(counter(1))).unwrap();
+
+        let mut flash = shutdown(storage);
+        flash.inject_power_loss(count);
+        let storage = boot(flash);
+        assert!(block_on(storage.factory_reset()).is_err());
+
+        let mut flash = shutdown(storage);
+        flash.power_cycle();
+        let storage = boot(flash);
+        assert_eq!(block_on(storage.fetch::<Counter>()).unwrap(), None);
+    }
+}
+
+#[test]
+fn failed_erase_is_reported() {
+    let mut flash = Flash::new();
+    flash.inject_erase_failure(Some(1));
+    assert!(matches!(
+        block_on(Storage::new(flash, RANGE, Some(JOURNAL_RANGE))),
+        Err(Error::Flash(sequential_storage::Error::Storage {
+            value: RamFlashError::EraseFailure,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn failed_erase_while_reclaiming_keeps_latest() {
+    let storage = boot(Flash::new());
+    let mut flash = shutdown(storage);
+    flash.inject_erase_failure(Some(1));
+    let storage = boot(flash);
+
+    // Keep storing until a page has to be reclaimed.
+    let mut stored = None;
+    for value in 0..10_000 {
+        match block_on(storage.store(counter(value))) {
+            Ok(()) => stored = Some(value),
+            Err(e) => {
+                assert!(matches!(
+                    e,
+                    Error::Flash(sequential_storage::Error::Storage {
+                        value: RamFlashError::EraseFailure,
+                        ..
+                    })
+                ));
+                break;
+            }
+        }
+    }
+    let stored = stored.unwrap();
+    assert!(stored < 9_999, "No page was reclaimed");
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(counter(stored))
+    );
+
+    // Once the page can be erased again, storage continues.
+    let mut flash = shutdown(storage);
+    flash.inject_erase_failure(None);
+    let storage = boot(flash);
+    block_on(storage.store(counter(u32::MAX))).unwrap();
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(counter(u32::MAX))
+    );
+    assert!(block_on(storage.health()).unwrap().wear.gc_count > 0);
+}
+
+#[test]
+fn migrates_versioned_entry() {
+    let storage = boot(Flash::new());
+    block_on(storage.store(CounterV0 { value: 7 })).unwrap();
+
+    let migrated = Counter {
+        value: 7,
+        label: false,
+    };
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(migrated)
+    );
+
+    // Upgraded in place, such that it is only migrated once.
+    {
+        let mut guard = block_on(storage.inner.lock());
+        let raw = block_on(guard.fetch_raw(ItemKey::versioned(Counter::KEY)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw[0], Counter::VERSION);
+    }
+
+    let storage = boot(shutdown(storage));
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(migrated)
+    );
+}
+
+#[test]
+fn migrates_legacy_entry() {
+    let storage = boot(Flash::new());
+    {
+        // As stored before the introduction of versioning, without the version header.
+        let mut guard = block_on(storage.inner.lock());
+        let inner = &mut *guard;
+        let mut raw = [0; 8];
+        let raw = postcard::to_slice(&CounterV0 { value: 5 }, &mut raw).unwrap();
+        block_on(sequential_storage::map::store_item(
+            &mut inner.flash,
+            inner.range.clone(),
+            &mut inner.cache,
+            &mut inner.buffer,
+            ItemKey::legacy(Counter::KEY),
+            &&*raw,
+        ))
+        .unwrap();
+    }
+
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(Counter {
+            value: 5,
+            label: false,
+        })
+    );
+}
+
+#[test]
+fn rejects_newer_version() {
+    let storage = boot(Flash::new());
+    block_on(storage.store(CounterV2 { value: 1 })).unwrap();
+
+    assert!(matches!(
+        block_on(storage.fetch::<Counter>()),
+        Err(Error::Serialization(SerializationError::InvalidFormat))
+    ));
+}
+
+#[test]
+fn factory_reset_erases_entries() {
+    let storage = boot(Flash::new());
+    block_on(storage.store(counter(1))).unwrap();
+    block_on(storage.append(&counter(2))).unwrap();
+    let erase_counts = block_on(storage.health()).unwrap().wear.erase_counts;
+
+    let mut subscriber = storage.notifier.subscriber().unwrap();
+    block_on(storage.factory_reset()).unwrap();
+    assert_eq!(
+        subscriber.try_next_message_pure(),
+        Some(Event::FactoryReset)
+    );
+    drop(subscriber);
+
+    let storage = boot(shutdown(storage));
+    assert_eq!(block_on(storage.fetch::<Counter>()).unwrap(), None);
+    assert_eq!(block_on(storage.latest::<Counter, 4>()).unwrap().len(), 0);
+
+    // The wear of the flash is retained.
+    let wear = block_on(storage.health()).unwrap().wear;
+    assert!(wear
+        .erase_counts
+        .iter()
+        .zip(erase_counts)
+        .all(|(&after, before)| after > before));
+}
+
+#[test]
+fn lists_all_keys() {
+    for determinator in 0..VERSIONED {
+        if let Ok(key) = StorageKey::try_from(determinator) {
+            assert!(StorageKey::ALL.contains(&key), "{:?} is missing", key);
+        }
+    }
+    assert_eq!(
+        (0..VERSIONED)
+            .filter(|&determinator| StorageKey::try_from(determinator).is_ok())
+            .count(),
+        StorageKey::COUNT
+    );
+}
+
+#[test]
+fn cache_prevents_scans() {
+    let storage = boot(Flash::new());
+    for value in 0..400 {
+        block_on(storage.store(counter(value))).unwrap();
+    }
+
+    let reads = |storage: &Storage<Flash>| block_on(storage.inner.lock()).flash.flash.reads;
+    let fetch = |storage: &Storage<Flash>| {
+        let before = reads(storage);
+        assert_eq!(
+            block_on(storage.fetch::<Counter>()).unwrap(),
+            Some(counter(399))
+        );
+        reads(storage) - before
+    };
+
+    // After booting, the pages are scanned to find the item.
+    let storage = boot(shutdown(storage));
+    let cold = fetch(&storage);
+
+    // Afterwards, only the header and the data of the item are read.
+    let warm = fetch(&storage);
+    assert_eq!(warm, 2);
+    assert!(cold > 10 * warm, "Scanning took only {} reads", cold);
+}
+
+#[test]
+fn estimates_free_space() {
+    let storage = boot(Flash::new());
+    let free = |storage: &Storage<Flash>| block_on(storage.health()).unwrap().free_bytes;
+
+    // All but the page markers, the storage marker, the journal marker and the wear.
+    let pages = RANGE.len() / PAGE_SIZE;
+    let initial = free(&storage);
+    assert!(initial < (pages * (PAGE_SIZE - 8)) as u32);
+    assert!(initial > (pages * (PAGE_SIZE - 8) - 128) as u32);
+
+    // An item header of 8 bytes, and the key, version and value padded to a word.
+    block_on(storage.store(counter(1))).unwrap();
+    assert_eq!(free(&storage), initial - 12);
+}
+
+#[test]
+fn max_size_bounds_serialized_size() {
+    fn serialized_size<T: Serialize>(value: &T) -> usize {
+        postcard::to_slice(value, &mut [0; 1024]).unwrap().len()
+    }
+
+    assert_eq!(serialized_size(&u16::MAX), u16::MAX_SIZE);
+    assert_eq!(serialized_size(&u32::MAX), u32::MAX_SIZE);
+    assert_eq!(serialized_size(&u64::MAX), u64::MAX_SIZE);
+
+    let mut vec = heapless::Vec::<u32, 128>::new();
+    vec.resize(128, u32::MAX).unwrap();
+    assert_eq!(serialized_size(&vec), heapless::Vec::<u32, 128>::MAX_SIZE);
+
+    let string = heapless::String::<127>::try_from("x".repeat(127).as_str()).unwrap();
+    assert_eq!(serialized_size(&string), heapless::String::<127>::MAX_SIZE);
+
+    let largest = Counter {
+        value: u32::MAX,
+        label: true,
+    };
+    assert_eq!(serialized_size(&largest), Counter::MAX_SIZE);
+}
+
+#[test]
+fn corrupted_entry_is_skipped() {
+    let storage = boot(Flash::new());
+    block_on(storage.store(counter(0x0123_4567))).unwrap();
+    block_on(storage.store(counter(0x7654_3210))).unwrap();
+
+    // Flip a bit in the value of the latest item, such that it no longer matches its checksum.
+    let mut flash = shutdown(storage);
+    let mut raw = [0; 8];
+    let raw = postcard::to_slice(&counter(0x7654_3210), &mut raw).unwrap();
+    let offset = flash
+        .as_bytes()
+        .windows(raw.len())
+        .rposition(|window| window == raw)
+        .unwrap();
+    flash.flip_bit(offset as u32, 0);
+
+    // The previous value is returned instead.
+    let storage = boot(flash);
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(counter(0x0123_4567))
+    );
+
+    // Storing over the corrupted item recovers.
+    block_on(storage.store(counter(1))).unwrap();
+    let storage = boot(shutdown(storage));
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(counter(1))
+    );
+}
+
+#[test]
+fn full_partition_keeps_entries() {
+    const SIZE: usize = 1500;
+
+    macro_rules! blob {
+        ($name:ident, $key:ident) => {
+            #[derive(Serialize, Deserialize)]
+            struct $name(heapless::Vec<u8, SIZE>);
+
+            impl MaxSize for $name {
+                const MAX_SIZE: usize = heapless::Vec::<u8, SIZE>::MAX_SIZE;
+            }
+
+            impl StorageEntry for $name {
+                const KEY: StorageKey = StorageKey::$key;
+            }
+        };
+    }
+    blob!(First, Presets);
+    blob!(Second, WifiCredentials);
+    blob!(Third, BrokerSettings);
+
+    // Only a single page is usable, as the other is kept free to reclaim pages.
+    let range = 0..(2 * PAGE_SIZE) as u32;
+    let storage = block_on(Storage::new(Flash::new(), range.clone(), None)).unwrap();
+    let blob = || heapless::Vec::from_slice(&[0xA5; SIZE]).unwrap();
+    block_on(storage.store(First(blob()))).unwrap();
+    block_on(storage.store(Second(blob()))).unwrap();
+
+    let full = |res| {
+        assert!(matches!(
+            res,
+            Err(Error::Flash(sequential_storage::Error::FullStorage))
+        ))
+    };
+    full(block_on(storage.store(Third(blob()))));
+    // Neither can existing entries be replaced, as the old and new value do not fit together.
+    full(block_on(storage.store(First(blob()))));
+
+    // Smaller entries still fit, and nothing was lost.
+    block_on(storage.store(counter(1))).unwrap();
+    let storage = block_on(Storage::new(shutdown(storage), range, None)).unwrap();
+    assert!(block_on(storage.fetch::<First>()).unwrap().is_some());
+    assert!(block_on(storage.fetch::<Second>()).unwrap().is_some());
+    assert!(block_on(storage.fetch::<Third>()).unwrap().is_none());
+    assert_eq!(
+        block_on(storage.fetch::<Counter>()).unwrap(),
+        Some(counter(1))
+    );
+}
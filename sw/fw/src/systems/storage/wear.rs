@@ -0,0 +1,168 @@
+//! Accounting of how hard the flash is worked.
+
+use core::ops::Range;
+
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use serde::{Deserialize, Serialize};
+
+use super::{MaxSize, StorageEntry, StorageKey, MAX_PAGES};
+
+/// Erase count of a page past which it is considered worn, with flash typically rated for 100k cycles.
+pub const WEAR_WARNING_ERASES: u32 = 80_000;
+
+/// Counters of operations on the flash, accumulated over the lifetime of the device.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Wear {
+    /// Number of erases of each page of the storage.
+    pub erase_counts: [u32; MAX_PAGES],
+    /// Number of erases of the journal.
+    pub journal_erase_count: u32,
+    /// Number of times a page had to be reclaimed to store an entry.
+    pub gc_count: u32,
+    pub write_failures: u32,
+}
+
+impl MaxSize for Wear {
+    const MAX_SIZE: usize = <[u32; MAX_PAGES]>::MAX_SIZE + 3 * u32::MAX_SIZE;
+}
+
+impl StorageEntry for Wear {
+    const KEY: StorageKey = StorageKey::Wear;
+}
+
+impl Wear {
+    pub fn max_erase_count(&self) -> u32 {
+        self.erase_counts.iter().copied().max().unwrap_or_default()
+    }
+
+    pub(super) fn total_erase_count(&self) -> u32 {
+        self.erase_counts.iter().sum::<u32>() + self.journal_erase_count
+    }
+}
+
+/// Flash health, as published.
+#[derive(PartialEq, Debug, Serialize, Clone)]
+pub struct Health {
+    pub wear: Wear,
+    /// Estimate of the number of bytes that can be written before a page has to be reclaimed.
+    pub free_bytes: u32,
+    pub wear_warning: bool,
+}
+
+/// Flash that keeps track of the wear of the storage range.
+pub(super) struct Metered<F> {
+    pub flash: F,
+    range: Range<u32>,
+    pub wear: Wear,
+    /// Whether the counters changed since they were last persisted.
+    pub dirty: bool,
+}
+
+impl<F: NorFlash> Metered<F> {
+    pub fn new(flash: F, range: Range<u32>) -> Self {
+        Self {
+            flash,
+            range,
+            wear: Wear::default(),
+            dirty: false,
+        }
+    }
+
+    /// Estimate the free space in the storage range, by inspecting the markers of each page.
+    ///
+    /// Pages are filled front to back, hence the erased tail of a page that is in use is free.
+    ///
+    /// sequential-storage has no API for this, hence this relies on its layout of a marker word at the start
+    /// and end of each page. The dependency is pinned for this reason, and the layout is checked by a test.
+    pub async fn free_bytes(&mut self) -> Result<u32, F::Error> {
+        let mut free = 0;
+        for page in self.range.clone().step_by(F::ERASE_SIZE) {
+            let start_marked = self.is_marked(page).await?;
+            let end_marked = self
+                .is_marked(page + (F::ERASE_SIZE - F::READ_SIZE) as u32)
+                .await?;
+
+            free += match (start_marked, end_marked) {
+                (false, false) => (F::ERASE_SIZE - 2 * F::READ_SIZE) as u32,
+                (true, false) => self.erased_tail(page).await?,
+                _ => 0,
+            };
+        }
+        Ok(free)
+    }
+
+    async fn is_marked(&mut self, offset: u32) -> Result<bool, F::Error> {
+        let mut word = [0u8; 4];
+        let word = &mut word[..F::READ_SIZE.min(4)];
+        self.flash.read(offset, word).await?;
+        Ok(word.iter().any(|&b| b != 0xFF))
+    }
+
+    /// Count the erased bytes at the end of the page, excluding the end marker.
+    async fn erased_tail(&mut self, page: u32) -> Result<u32, F::Error> {
+        const CHUNK_SIZE: usize = 128;
+
+        let mut chunk = [0u8; CHUNK_SIZE];
+        let data_start = page + F::READ_SIZE as u32;
+        let mut end = page + (F::ERASE_SIZE - F::READ_SIZE) as u32;
+        let mut erased = 0;
+
+        while end > data_start {
+            let len = (end - data_start).min(CHUNK_SIZE as u32);
+            let chunk = &mut chunk[..len as usize];
+            self.flash.read(end - len, chunk).await?;
+
+            let tail = chunk.iter().rev().take_while(|&&b| b == 0xFF).count() as u32;
+            erased += tail;
+            if tail < len {
+                break;
+            }
+            end -= len;
+        }
+        Ok(erased)
+    }
+}
+
+impl<F: NorFlash> ErrorType for Metered<F> {
+    type Error = F::Error;
+}
+
+impl<F: NorFlash> ReadNorFlash for Metered<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for Metered<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        for page in (from..to).step_by(F::ERASE_SIZE) {
+            if self.range.contains(&page) {
+                let index = ((page - self.range.start) as usize) / F::ERASE_SIZE;
+                self.wear.erase_counts[index] += 1;
+            } else {
+                self.wear.journal_erase_count += 1;
+            }
+        }
+        self.dirty = true;
+
+        self.flash.erase(from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let res = self.flash.write(offset, bytes).await;
+        if res.is_err() {
+            self.wear.write_failures += 1;
+            self.dirty = true;
+        }
+        res
+    }
+}
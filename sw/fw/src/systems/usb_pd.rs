@@ -7,13 +7,36 @@ use static_cell::StaticCell;
 
 use crate::{
     bsp::{self, I2cBusDevice, I2cError},
-    drivers::stusb4500::{hl::STUSB4500, FixedPdo},
+    drivers::stusb4500::{
+        hl::{self, STUSB4500},
+        ll::registers::rdo_status,
+        FixedPdo, PolicyEngineFSMState,
+    },
+    util::{Milliamps, Millivolts},
 };
 
 pub struct Usbpd {
     hl: Mutex<CriticalSectionRawMutex, STUSB4500<I2cBusDevice, I2cError>>,
+    contract: Mutex<CriticalSectionRawMutex, Option<Contract>>,
 }
 
+/// Power negotiated with the source.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Contract {
+    pub voltage: Millivolts,
+    pub current: Milliamps,
+}
+
+impl Contract {
+    pub fn power_mw(&self) -> u32 {
+        self.voltage.0 as u32 * self.current.0 as u32 / 1000
+    }
+}
+
+/// Voltages of the PDO's we request as a sink.
+const PDO1_VOLTAGE: Millivolts = Millivolts(5000);
+const PDO2_VOLTAGE: Millivolts = Millivolts(20000);
+
 const NVM_DATA: [[u8; 8]; 5] = [
     [0x00, 0x00, 0xB0, 0xAB, 0x00, 0x45, 0x00, 0x00],
     [0x00, 0x40, 0x9C, 0x1C, 0xFF, 0x01, 0x3C, 0xDF],
@@ -43,7 +66,7 @@ impl Usbpd {
 
         let mut hl = nvm.lock_nvm().await.unwrap();
 
-        let pdo = FixedPdo::new(20000 / 50, 1000 / 10);
+        let pdo = FixedPdo::new(PDO2_VOLTAGE.0 / 50, 1000 / 10);
         hl.set_pdo(crate::drivers::stusb4500::PdoChannel::PDO2, pdo)
             .await
             .unwrap();
@@ -54,13 +77,29 @@ impl Usbpd {
         let hl = Mutex::new(hl);
 
         static USBPD: StaticCell<Usbpd> = StaticCell::new();
-        let system = USBPD.init(Self { hl });
+        let system = USBPD.init(Self {
+            hl,
+            contract: Mutex::new(None),
+        });
 
         spawner.must_spawn(state_task(system));
 
         system
     }
 
+    /// The currently negotiated contract, if any.
+    pub async fn contract(&self) -> Option<Contract> {
+        *self.contract.lock().await
+    }
+
+    /// State of the policy engine, and the request object last sent to the source.
+    pub async fn status(
+        &self,
+    ) -> Result<(PolicyEngineFSMState, rdo_status::R), hl::Error<I2cError>> {
+        let mut hl = self.hl.lock().await;
+        Ok((hl.fsm_state().await?, hl.rdo().await?))
+    }
+
     /// Set output GPIO pin value. (connected to LED indicating a short)
     pub async fn set_pin(&self, level: bool) {
         let mut hl = self.hl.lock().await;
@@ -85,6 +124,29 @@ async fn state_task(system: &'static Usbpd) {
             log::info!("{:?} => {:?}", prev_state, state);
             log::info!("{:?}", rdo);
             prev_state = state;
+
+            let ready = matches!(
+                state,
+                PolicyEngineFSMState::SnkReady | PolicyEngineFSMState::SnkReadySending
+            );
+            let contract = if ready {
+                // The first source PDO is always vSafe5V, any other is matched against our highest PDO.
+                let voltage = if rdo.object_position() == 1 {
+                    PDO1_VOLTAGE
+                } else {
+                    PDO2_VOLTAGE
+                };
+
+                Some(Contract {
+                    voltage,
+                    current: Milliamps(rdo.current() * 10),
+                })
+            } else {
+                None
+            };
+
+            log::info!("Contract {:?}", contract);
+            *system.contract.lock().await = contract;
         }
         Timer::after(Duration::from_millis(50)).await;
     }
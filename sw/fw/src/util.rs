@@ -5,6 +5,8 @@ use embassy_sync::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::systems::storage::MaxSize;
+
 // pub mod statsbuffer;
 
 const DATA_CAP: usize = 1;
@@ -23,7 +25,7 @@ impl Debug for Nanovolts {
     }
 }
 
-#[derive(PartialEq, Serialize, Deserialize, Default, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default, Clone, Copy)]
 pub struct Millivolts(pub u16);
 
 impl Debug for Millivolts {
@@ -32,15 +34,23 @@ impl Debug for Millivolts {
     }
 }
 
+impl MaxSize for Millivolts {
+    const MAX_SIZE: usize = u16::MAX_SIZE;
+}
+
 impl From<Nanovolts> for Millivolts {
     fn from(value: Nanovolts) -> Self {
         Millivolts((value.0 / 1_000_000) as u16)
     }
 }
 
-#[derive(PartialEq, Serialize, Deserialize, Default, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default, Clone, Copy)]
 pub struct Milliamps(pub u16);
 
+impl MaxSize for Milliamps {
+    const MAX_SIZE: usize = u16::MAX_SIZE;
+}
+
 impl Debug for Milliamps {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_fmt(format_args!("{}mA", self.0))